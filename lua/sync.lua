@@ -10,6 +10,9 @@ local fs_makeDir = fs.makeDir
 local string_sub = string.sub
 local os_date = os.date
 
+-- has to match PROTOCOL_VERSION on the server, which refuses anything else
+local protocolVersion = 6
+
 local function split(input, delimiter)
   local result = {}
   for part in string.gmatch(input, "([^" .. delimiter .. "]+)") do
@@ -29,25 +32,78 @@ if not address then
   return
 end
 
+-- set by /download when it was fetched with ?token=, needed when the server has auth tokens
+local token = settings.get("sync.token")
+local function withToken(url, separator)
+  if not token then return url end
+  return url .. separator .. "token=" .. textutils.urlEncode(token)
+end
+
 local function trim(s) return s:match'^()%s*$' and '' or s:match'^%s*(.*%S)' end
 
 if not arg[2] then 
-  local request = http.get("http://" .. address)
+  local request, err = http.get(withToken("http://" .. address .. "/", "?"))
+  if not request then
+    print("failed to list channels: " .. tostring(err))
+    return
+  end
   local raw = libDeflate:DecompressDeflate(request.readAll())
   textutils.pagedPrint("available channels:\n"..trim(raw))
   return
 end
 
+-- hashes of every file synced to this computer, sent on connect so the server can skip files that haven't changed
+local manifestPath = "/cc-sync/manifest"
+local manifest = {}
+if fs_exists(manifestPath) then
+  local f = fs_open(manifestPath, "r")
+  manifest = textutils.unserialize(f.readAll()) or {}
+  f.close()
+end
+
+local function saveManifest()
+  local f = fs_open(manifestPath, "w")
+  f.write(textutils.serialize(manifest))
+  f.close()
+end
+
 local channels = { select(2, unpack(arg)) }
 local ws_addr = "ws://" .. address .. "/subscribe?channels=" .. table.concat(channels, ",")
+-- the token stays off the screen, anyone looking at the computer could read it
 print("connecting to address " .. ws_addr)
-local ws, err = http.websocket(ws_addr)
+local ws, err = http.websocket(withToken(ws_addr, "&"))
 if not ws then
   print("failed when connecting")
   print(err)
   return
 end
 
+local manifestFiles = nil
+for path, hash in pairs(manifest) do
+  if fs_exists(path) and not fs.isDir(path) then
+    manifestFiles = manifestFiles or {}
+    manifestFiles[path] = { hash = hash, size = fs.getSize(path) }
+  end
+end
+ws.send(textutils.serializeJSON({ type = "hello", version = protocolVersion }))
+ws.send(textutils.serializeJSON({ type = "client_info", id = os.getComputerID(), label = os.getComputerLabel() }))
+ws.send(textutils.serializeJSON({ type = "manifest", files = manifestFiles }))
+
+-- with `set sync.forward_logs true` everything other programs print is sent to the server too, sync.lua's own output isn't
+local originalPrint, originalPrintError = _G.print, _G.printError
+local function forward(original, isError)
+  return function(...)
+    local t = table.pack(...)
+    for i = 1, t.n do t[i] = tostring(t[i]) end
+    pcall(ws.send, textutils.serializeJSON({ type = "log", line = table.concat(t, "\t"), error = isError }))
+    return original(...)
+  end
+end
+if settings.get("sync.forward_logs") then
+  _G.print = forward(originalPrint, false)
+  _G.printError = forward(originalPrintError, true)
+end
+
 local function decode(data)
   local ret = {}
   local len = #data
@@ -58,46 +114,187 @@ local function decode(data)
     if tag == 0 then
       local fp_len = string_unpack(">I4", data, offset)
       local fd_len = string_unpack(">I4", data, offset + 4)
-      local fp = string_sub(data, offset, offset + 8 + fp_len - 1)
-      offset = offset + 8 + fp_len
+      local s = string_unpack(">I4", data, offset + 8)
+      local c = string_unpack(">I4", data, offset + 12)
+      local t = string_unpack(">I4", data, offset + 16)
+      local h = string_sub(data, offset + 20, offset + 20 + 16 - 1)
+      offset = offset + 20 + 16
+      local fp = string_sub(data, offset, offset + fp_len - 1)
+      offset = offset + fp_len
       local fd = string_sub(data, offset, offset + fd_len - 1)
       offset = offset + fd_len
-      table_insert(ret, {fp = fp, fd = fd})
+      table_insert(ret, {fp = fp, fd = fd, h = h, s = s, c = c, t = t})
     elseif tag == 1 then
       local strings = {}
       local string_len = string_unpack(">I4", data, offset)
       offset = offset + 4
       for i = 1, string_len do
         local l = string_unpack(">I4", data, offset)
-        table_insert(strings, string_sub(data, offset, offset + 4 + l - 1))
+        table_insert(strings, string_sub(data, offset + 4, offset + 4 + l - 1))
         offset = offset + 4 + l
       end
       table_insert(ret, {f = strings})
     elseif tag == 2 then
-      local chunk_len = string_unpack(">I4", data, offset)
-      table_insert(ret, {fd = string_sub(data, offset, offset + 4 + chunk_len - 1)})
-      offset = offset + 4 + chunk_len
+      local t, i, n, chunk_len = string_unpack(">I4>I4>I4>I4", data, offset)
+      table_insert(ret, {t = t, i = i, n = n, fd = string_sub(data, offset + 16, offset + 16 + chunk_len - 1)})
+      offset = offset + 16 + chunk_len
+    elseif tag == 3 then
+      local renames = {}
+      local count = string_unpack(">I4", data, offset)
+      offset = offset + 4
+      for i = 1, count do
+        local from_len = string_unpack(">I4", data, offset)
+        local from = string_sub(data, offset + 4, offset + 4 + from_len - 1)
+        offset = offset + 4 + from_len
+        local to_len = string_unpack(">I4", data, offset)
+        local to = string_sub(data, offset + 4, offset + 4 + to_len - 1)
+        offset = offset + 4 + to_len
+        table_insert(renames, { from = from, to = to })
+      end
+      table_insert(ret, {r = renames})
     end
     if offset > len then break end
   end
   return ret
 end
 
+-- files in bidirectional channels that are synced as is, edits to them are sent back to the server
+local uploadable = {}
+-- what sync.lua last wrote or uploaded for each file, anything else on disk is an edit made on the computer
+local known = {}
+local editInterval = 1
+local editTimer = os.startTimer(editInterval)
+
+local function remember(path, content)
+  local attributes = fs.attributes(path)
+  known[path] = { size = #content, checksum = libDeflate:Adler32(content), modified = attributes and attributes.modified }
+end
+
+local function checkEdits()
+  for path in pairs(uploadable) do
+    local attributes = fs_exists(path) and fs.attributes(path)
+    local last = known[path]
+    if attributes and last and attributes.modified ~= last.modified then
+      local f = fs_open(path, "rb")
+      local content = f.readAll() or ""
+      f.close()
+      if #content ~= last.size or libDeflate:Adler32(content) ~= last.checksum then
+        print("[" .. os_date("%H:%M:%S") .. "] uploading edit of " .. path)
+        -- the hash tells the server which version was edited, so it can tell when the file also changed on its side
+        local base = string_sub((manifest[path] or "") .. string.rep("0", 16), 1, 16)
+        ws.send(string.pack(">I1>I4", 0, #path) .. base .. path .. content, true)
+      end
+      remember(path, content)
+    end
+  end
+end
+
+-- commands sent by the server, each runs in its own coroutine that's resumed with every event like the shell would.
+-- everything written while one runs is sent back along with whether it succeeded
+local running = {}
+local capture = nil
+local originalWrite = _G.write
+_G.write = function(text)
+  if capture then table_insert(capture, tostring(text)) end
+  return originalWrite(text)
+end
+
+-- returns true once the command finished
+local function resumeCommand(command, ...)
+  capture = command.output
+  local ok, filter = coroutine.resume(command.co, ...)
+  capture = nil
+  if not ok then
+    table_insert(command.output, tostring(filter))
+    command.success = false
+  end
+  if coroutine.status(command.co) == "dead" then
+    ws.send(textutils.serializeJSON({ type = "command_result", id = command.id, success = command.success, output = table.concat(command.output) }))
+    return true
+  end
+  command.filter = filter
+  return false
+end
+
+local function startCommand(id, program, args)
+  print("[" .. os_date("%H:%M:%S") .. "] running " .. program .. " " .. table.concat(args, " "))
+  local command = { id = id, output = {}, success = false }
+  command.co = coroutine.create(function()
+    command.success = shell.run(program, table.unpack(args))
+  end)
+  if not resumeCommand(command) then table_insert(running, command) end
+end
+
 local function receive() 
-  local ev, ev1, ev2, ev3 = os.pullEventRaw()
+  local event = table.pack(os.pullEventRaw())
+  local ev, ev1, ev2, ev3 = table.unpack(event, 1, 4)
+  for i = #running, 1, -1 do
+    local command = running[i]
+    if command.filter == nil or command.filter == ev or ev == "terminate" then
+      if resumeCommand(command, table.unpack(event, 1, event.n)) then table.remove(running, i) end
+    end
+  end
   if ev == "websocket_closed" then
+    if ev2 then print("connection closed: "..ev2) end
     return nil, true
   end
   if ev == "terminate" then
     ws.close()
     return nil, true
   end
+  -- other programs change what this computer is subscribed to with os.queueEvent("sync_subscribe", "channel", ...).
+  -- channels is only updated once the server answers, it leaves out channels that don't exist or need a token
+  if ev == "sync_subscribe" or ev == "sync_unsubscribe" then
+    local changed = { table.unpack(event, 2, event.n) }
+    if #changed > 0 then
+      ws.send(textutils.serializeJSON({ type = string.sub(ev, 6), channels = changed }))
+    end
+    return nil, false
+  end
+  if ev == "timer" and ev1 == editTimer then
+    checkEdits()
+    editTimer = os.startTimer(editInterval)
+    return nil, false
+  end
   if ev ~= "websocket_message" then
     return nil, false
   end
   local recv, isBinary = ev2, ev3
   if not recv then print("websocket likely closed, ending program") return nil, true end
-  if not isBinary then error("non-binary message received:\n"..recv) return nil, true end
+  if not isBinary then
+    local message = textutils.unserializeJSON(recv)
+    if message and message.type == "hello" then
+      if message.version ~= protocolVersion then
+        print("server uses protocol " .. tostring(message.version) .. " but this sync.lua uses " .. protocolVersion .. ", re-run /download")
+        ws.close()
+        return nil, true
+      end
+      return nil, false
+    end
+    if message and message.type == "run" then
+      startCommand(message.id, message.program, message.args or {})
+      return nil, false
+    end
+    if message and message.type == "reboot" then
+      print("[" .. os_date("%H:%M:%S") .. "] rebooting")
+      ws.send(textutils.serializeJSON({ type = "command_result", id = message.id, success = true }))
+      saveManifest()
+      ws.close()
+      os.reboot()
+    end
+    if message and message.type == "bidirectional" then
+      uploadable = {}
+      for _, path in ipairs(message.files) do uploadable[path] = true end
+      return nil, false
+    end
+    if message and message.type == "channels" then
+      channels = message.channels
+      os.queueEvent("channel_update", channels)
+      return nil, false
+    end
+    error("unexpected text message received:\n"..recv)
+    return nil, true
+  end
   if recv then
     local rdata = libDeflate:DecompressDeflate(recv)
     local data = decode(rdata)
@@ -111,29 +308,13 @@ local function receive()
   end
 end
 
+-- files are always written in binary mode so resources (and non-ascii text) come out byte for byte
 local function ensureFile(path, data)
-  local dir = split(path, "/")
-  local currentDir = dir[1]
-  if #dir == 1 then
-    local f = fs_open(currentDir, "w+")
-    f.write(data)
-    f.close()
-    return
-  end
-  if #dir > 2 then
-	  for i = 2, #currentDir - 1 do
-      if i == #dir then break end
-      if not dir[i] then break end
-		  currentDir = currentDir .. "/" .. dir[i]
-		  if not fs_exists(currentDir) then 
-		    fs_makeDir(currentDir)
-		  end
-	  end
-  else
-    if not fs_exists(currentDir) then fs_makeDir(currentDir) end
+  local dir = fs.getDir(path)
+  if dir ~= "" and not fs_exists(dir) then
+    fs_makeDir(dir)
   end
-  currentDir = currentDir .. "/" .. dir[#dir]
-  local file = fs_open(currentDir, "w")
+  local file = fs_open(path, "wb")
   file.write(data)
   file.close()
 end
@@ -158,28 +339,116 @@ local function walkUpTree(path)
   checkFolder(currentPath)
 end
 
-local lastFile = ""
+-- files that arrive in several pieces, by transfer id. pieces are kept in memory until every one of them is here,
+-- the first piece (which has the path) can come after chunks of the same file
+local transfers = {}
+-- how many times each file was asked for again, so a file that can never be written correctly doesn't loop forever
+local retries = {}
+local maxRetries = 3
+-- files written and verified since the last ack was sent
+local acked = {}
 
-local function addPortion(data)
-  local f = fs_open(lastFile, "a")
-  f.write(data.fd)
-  f.close()
+-- compares what ended up on disk to the size and checksum the server sent, asking for the file again when they differ
+local function verify(file)
+  local f = fs_open(file.path, "rb")
+  local content = f and f.readAll() or ""
+  if f then f.close() end
+  if #content == file.size and libDeflate:Adler32(content) == file.checksum then
+    retries[file.path] = nil
+    table_insert(acked, file.path)
+    return true
+  end
+  manifest[file.path] = nil
+  retries[file.path] = (retries[file.path] or 0) + 1
+  if retries[file.path] > maxRetries then
+    print("[" .. os_date("%H:%M:%S") .. "] " .. file.path .. " failed verification " .. maxRetries .. " times, giving up")
+    return
+  end
+  print("[" .. os_date("%H:%M:%S") .. "] " .. file.path .. " failed verification, requesting it again")
+  ws.send(textutils.serializeJSON({ type = "resend", path = file.path }))
+end
+
+local function writeFile(path, content, hash, size, checksum)
+  ensureFile(path, content)
+  manifest[path] = hash
+  if verify({ path = path, size = size, checksum = checksum }) then
+    remember(path, content)
+  end
+end
+
+local function addPiece(id, index, total, fd)
+  local transfer = transfers[id]
+  if not transfer then
+    transfer = { pieces = {}, received = 0 }
+    transfers[id] = transfer
+  end
+  if transfer.pieces[index] == nil then
+    transfer.pieces[index] = fd
+    transfer.received = transfer.received + 1
+  end
+  transfer.total = transfer.total or total
+  if transfer.path and transfer.total and transfer.received >= transfer.total then
+    transfers[id] = nil
+    writeFile(transfer.path, table.concat(transfer.pieces, "", 0, transfer.total - 1), transfer.hash, transfer.size, transfer.checksum)
+  end
+  return transfer
+end
+
+-- moves whatever sync.lua tracks about from (or anything under it, for directories) over to to
+local function rekey(t, from, to)
+  local moved = {}
+  for path, value in pairs(t) do
+    if path == from or string_sub(path, 1, #from + 1) == from .. "/" then
+      moved[to .. string_sub(path, #from + 1)] = value
+      t[path] = nil
+    end
+  end
+  for path, value in pairs(moved) do t[path] = value end
+end
+
+local function renameFile(from, to)
+  if not fs_exists(from) then
+    -- nothing to move, so the file itself is needed. for directories the server sends everything in them
+    ws.send(textutils.serializeJSON({ type = "resend", path = to }))
+    return
+  end
+  if fs_exists(to) then fs_delete(to) end
+  local dir = fs.getDir(to)
+  if dir ~= "" and not fs_exists(dir) then
+    fs_makeDir(dir)
+  end
+  fs.move(from, to)
+  walkUpTree(from)
+  rekey(manifest, from, to)
+  rekey(known, from, to)
 end
 
 local function processData(data)
-  if data.f ~= nil then
+  if data.r ~= nil then
+    print("[" .. os_date("%H:%M:%S") .. "] processing rename sync request")
+    for _,v in ipairs(data.r) do
+      renameFile(v.from, v.to)
+    end
+  elseif data.f ~= nil then
     print("[" .. os_date("%H:%M:%S") .. "] processing deletion sync request")
     for _,v in pairs(data.f) do
       fs_delete(v)
       walkUpTree(v)
+      manifest[v] = nil
+      known[v] = nil
     end
   elseif data.fp == nil then
     print("[" .. os_date("%H:%M:%S") .. "] processing chunked sync request")
-    addPortion(data)
-  elseif data.fp ~= nil then
+    addPiece(data.t, data.i, data.n, data.fd)
+  elseif data.t ~= 0 then
     print("[" .. os_date("%H:%M:%S") .. "] processing data sync request")
-    ensureFile(data.fp, data.fd)
-    lastFile = data.fp
+    local transfer = transfers[data.t] or { pieces = {}, received = 0 }
+    transfers[data.t] = transfer
+    transfer.path, transfer.hash, transfer.size, transfer.checksum = data.fp, data.h, data.s, data.c
+    addPiece(data.t, 0, nil, data.fd)
+  else
+    print("[" .. os_date("%H:%M:%S") .. "] processing data sync request")
+    writeFile(data.fp, data.fd, data.h, data.s, data.c)
   end
 end
 
@@ -196,7 +465,14 @@ while true do
     for _,v in pairs(data) do
       processData(v)
     end
+    saveManifest()
+    if #acked > 0 then
+      ws.send(textutils.serializeJSON({ type = "ack", files = acked }))
+      acked = {}
+    end
     os.queueEvent("channel_update", channels)
     log_p()
   end
-end
\ No newline at end of file
+end
+
+_G.print, _G.printError, _G.write = originalPrint, originalPrintError, originalWrite
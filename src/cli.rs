@@ -0,0 +1,351 @@
+use std::{env::current_dir, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, process::ExitCode, sync::Arc};
+
+use clap::{Args, Parser, Subcommand};
+use parking_lot::RwLock;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+use crate::{config::{ProjectOverrides, get_bind_addresses, load_project}, server::{SyncServer, cache::ProcessedCache, installer::{InstallerCompression, build_installer}, util::{get_channel_output, get_export_path, get_full_channel_list, process_channels}}, structs::{CommandRequest, CommandResult, Project, RemoteCommand}};
+
+pub const PROJECT_TEMPLATE: &str = r#"Project(
+	root_dir: "src",
+	max_uncompressed_request_size: 100000,
+	port: 10234,
+	items: [
+		ProjectItem(
+			type: Library,
+			channel_name: "lib",
+			directories: [Directory(path: "lib")]
+		)
+	]
+)
+"#;
+
+#[derive(Parser)]
+#[command(version, about = "sync server for computercraft", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+	#[command(subcommand)]
+	command: Option<Command>,
+	// running without a command serves, so the serve flags work on their own too
+	#[command(flatten)]
+	serve: ServeArgs
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+	#[arg(long, default_value = "project.ron", help = "path to project.ron, root_dir is relative to the directory it's in")]
+	config: PathBuf
+}
+
+#[derive(Args)]
+struct ServeArgs {
+	#[command(flatten)]
+	config: ConfigArgs,
+	#[arg(long, help = "overrides port")]
+	port: Option<u16>,
+	#[arg(long, help = "overrides root_dir, relative to the current directory")]
+	root: Option<PathBuf>,
+	#[arg(long = "bind", help = "overrides bind_address, can be given multiple times")]
+	bind: Vec<String>
+}
+
+#[derive(Args)]
+struct CommandTarget {
+	#[command(flatten)]
+	config: ConfigArgs,
+	#[arg(long, help = "channel the computers are subscribed to")]
+	channel: String,
+	#[arg(long, help = "only the computer with this id")]
+	computer: Option<u32>
+}
+
+#[derive(Subcommand)]
+enum Command {
+	#[command(about = "start the sync server (default)")]
+	Serve(ServeArgs),
+	#[command(about = "validate the config and process every file without serving")]
+	Check(ConfigArgs),
+	#[command(about = "write the processed output of channels to a directory")]
+	Build {
+		#[command(flatten)]
+		config: ConfigArgs,
+		#[arg(long, help = "delete everything in the output directory before writing to it")]
+		clean: bool,
+		#[arg(help = "directory to write to, laid out the same way as the computer")]
+		out: PathBuf,
+		#[arg(help = "channels to export along with the channels they require, every channel when none are given")]
+		channels: Vec<String>
+	},
+	#[command(about = "create a single lua script that installs channels when ran on a computer")]
+	Installer {
+		#[command(flatten)]
+		config: ConfigArgs,
+		#[arg(long, value_enum, default_value_t = InstallerCompression::Lz4)]
+		compression: InstallerCompression,
+		#[arg(long, default_value = "installer.lua", help = "file to write the installer to")]
+		out: PathBuf,
+		#[arg(help = "channels to install along with the channels they require, every channel when none are given")]
+		channels: Vec<String>
+	},
+	#[command(about = "run a program on computers subscribed to a channel through the running server")]
+	Run {
+		#[command(flatten)]
+		target: CommandTarget,
+		#[arg(help = "program to run, along with its arguments")]
+		program: String,
+		#[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+		args: Vec<String>
+	},
+	#[command(about = "reboot computers subscribed to a channel through the running server")]
+	Reboot {
+		#[command(flatten)]
+		target: CommandTarget
+	},
+	#[command(about = "print channels along with the channels they require")]
+	List(ConfigArgs),
+	#[command(about = "create a project.ron to start from")]
+	Init(ConfigArgs)
+}
+
+pub async fn run(cli: Cli) -> ExitCode {
+	match cli.command {
+		None => serve(cli.serve).await,
+		Some(Command::Serve(args)) => serve(args).await,
+		Some(Command::Check(args)) => check(args),
+		Some(Command::Build { config, clean, out, channels }) => build(config, clean, out, channels),
+		Some(Command::Installer { config, compression, out, channels }) => installer(config, compression, out, channels),
+		Some(Command::Run { target, program, args }) => command(target, RemoteCommand::Run { program, args }).await,
+		Some(Command::Reboot { target }) => command(target, RemoteCommand::Reboot).await,
+		Some(Command::List(args)) => list(args),
+		Some(Command::Init(args)) => init(args)
+	}
+}
+
+// everything resolves paths against the current directory, so it's moved to wherever project.ron is
+fn enter_project_dir(config: &Path) -> Result<PathBuf, String> {
+	if !config.is_file() {
+		return Err(format!("{} not found", config.to_string_lossy()));
+	}
+	if let Some(dir) = config.parent() && !dir.as_os_str().is_empty() {
+		std::env::set_current_dir(dir).map_err(|e| format!("failed to enter {}: {}", dir.to_string_lossy(), e))?;
+	}
+	Ok(current_dir().unwrap().join(config.file_name().unwrap()))
+}
+
+fn load(config: &ConfigArgs, overrides: &ProjectOverrides) -> Option<(Project, PathBuf)> {
+	let cfg_path = match enter_project_dir(&config.config) {
+		Ok(path) => path,
+		Err(e) => {
+			println!("{}", e);
+			return None;
+		}
+	};
+	match load_project(&cfg_path, overrides) {
+		Ok(project) => Some((project, cfg_path)),
+		Err(errors) => {
+			for error in errors {
+				println!("{}", error);
+			}
+			None
+		}
+	}
+}
+
+async fn serve(args: ServeArgs) -> ExitCode {
+	let overrides = ProjectOverrides {
+		port: args.port,
+		// made absolute before moving to the project's directory
+		root_dir: args.root.map(|e| std::path::absolute(e).unwrap().to_string_lossy().to_string()),
+		bind_address: if args.bind.is_empty() { None } else { Some(args.bind) }
+	};
+	let Some((project, cfg_path)) = load(&args.config, &overrides) else {
+		return ExitCode::FAILURE;
+	};
+
+	let mut server = SyncServer::new(project, Some(cfg_path));
+	server.set_overrides(overrides);
+
+	match server.start_server().await.unwrap() {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(e) => {
+			println!("{}", e);
+			ExitCode::FAILURE
+		}
+	}
+}
+
+fn check(args: ConfigArgs) -> ExitCode {
+	let Some((project, _)) = load(&args, &ProjectOverrides::default()) else {
+		return ExitCode::FAILURE;
+	};
+	let mut errors = 0;
+	for item in &project.items {
+		for required in item.required_channels.iter().flatten() {
+			if !project.items.iter().any(|e| &e.channel_name == required) {
+				println!("channel {} requires {}, which doesn't exist", item.channel_name, required);
+				errors += 1;
+			}
+		}
+	}
+	let root = current_dir().unwrap().join(&project.root_dir);
+	let items = project.items.clone();
+	let cache = ProcessedCache::new();
+	let processed = process_channels(&Arc::new(RwLock::new(project)), &root, &items, &cache);
+	errors += cache.failures();
+	println!("processed {} file(s), {} error(s)", processed.len(), errors);
+	if errors == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+fn build(config: ConfigArgs, clean: bool, out: PathBuf, channels: Vec<String>) -> ExitCode {
+	let out = std::path::absolute(out).unwrap();
+	let Some((project, cfg_path)) = load(&config, &ProjectOverrides::default()) else {
+		return ExitCode::FAILURE;
+	};
+	// cleaning deletes the whole directory, which can't be allowed to take the project along.
+	// symlinks would hide that, so every path is compared canonicalized
+	if clean && out.exists() {
+		let out = out.canonicalize().unwrap_or_else(|_| out.clone());
+		let cfg_path = cfg_path.canonicalize().unwrap_or(cfg_path);
+		let root = current_dir().unwrap().join(&project.root_dir);
+		let root = root.canonicalize().unwrap_or(root);
+		if cfg_path.starts_with(&out) || root.starts_with(&out) || out.starts_with(&root) {
+			println!("refusing to clean {}, it contains project.ron or root_dir or is inside root_dir", out.to_string_lossy());
+			return ExitCode::FAILURE;
+		}
+	}
+	let channels = if channels.is_empty() { project.items.iter().map(|e| e.channel_name.clone()).collect() } else { channels };
+	let cache = ProcessedCache::new();
+	let files = match get_channel_output(&Arc::new(RwLock::new(project)), &channels, &cache) {
+		Ok(files) => files,
+		Err(errors) => {
+			for error in errors {
+				println!("{}", error);
+			}
+			return ExitCode::FAILURE;
+		}
+	};
+	if clean && out.exists() && let Err(e) = std::fs::remove_dir_all(&out) {
+		println!("failed to clean {}: {}", out.to_string_lossy(), e);
+		return ExitCode::FAILURE;
+	}
+	for file in &files {
+		let Some(path) = get_export_path(&out, &file.file_path) else {
+			println!("{} isn't a valid path to export to", file.file_path);
+			return ExitCode::FAILURE;
+		};
+		let result = std::fs::create_dir_all(path.parent().unwrap()).and_then(|_| std::fs::write(&path, &file.file_data));
+		if let Err(e) = result {
+			println!("failed to write {}: {}", path.to_string_lossy(), e);
+			return ExitCode::FAILURE;
+		}
+	}
+	println!("wrote {} file(s) to {}", files.len(), out.to_string_lossy());
+	ExitCode::SUCCESS
+}
+
+fn installer(config: ConfigArgs, compression: InstallerCompression, out: PathBuf, channels: Vec<String>) -> ExitCode {
+	let out = std::path::absolute(out).unwrap();
+	let Some((project, _)) = load(&config, &ProjectOverrides::default()) else {
+		return ExitCode::FAILURE;
+	};
+	let channels = if channels.is_empty() { project.items.iter().map(|e| e.channel_name.clone()).collect() } else { channels };
+	let cache = ProcessedCache::new();
+	let files = match get_channel_output(&Arc::new(RwLock::new(project)), &channels, &cache) {
+		Ok(files) => files,
+		Err(errors) => {
+			for error in errors {
+				println!("{}", error);
+			}
+			return ExitCode::FAILURE;
+		}
+	};
+	let script = build_installer(&files, &channels, compression);
+	if let Err(e) = std::fs::write(&out, &script) {
+		println!("failed to write {}: {}", out.to_string_lossy(), e);
+		return ExitCode::FAILURE;
+	}
+	println!("wrote an installer for {} file(s) to {} ({} bytes)", files.len(), out.to_string_lossy(), script.len());
+	ExitCode::SUCCESS
+}
+
+// sent to the server that's already running for the project, which forwards it to the computers
+async fn command(target: CommandTarget, command: RemoteCommand) -> ExitCode {
+	let Some((project, _)) = load(&target.config, &ProjectOverrides::default()) else {
+		return ExitCode::FAILURE;
+	};
+	let address = match get_bind_addresses(&project.bind_address).unwrap()[0] {
+		IpAddr::V4(e) if e.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+		IpAddr::V6(e) if e.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+		address => address
+	};
+	let body = serde_json::to_string(&CommandRequest { channel: target.channel, computer: target.computer, command }).unwrap();
+	// the project's token goes in the query since admin_token takes the header
+	let path = project.auth_token.as_ref().map(|e| format!("/commands?token={}", e)).unwrap_or("/commands".to_string());
+	let response = match post(SocketAddr::new(address, project.port), &path, &body, &project.admin_token).await {
+		Ok(response) => response,
+		Err(e) => {
+			println!("failed to reach the server on port {}, is it running? {}", project.port, e);
+			return ExitCode::FAILURE;
+		}
+	};
+	let Ok(results) = serde_json::from_str::<Vec<CommandResult>>(&response) else {
+		println!("{}", response.trim());
+		return ExitCode::FAILURE;
+	};
+	if results.is_empty() {
+		println!("no computers are subscribed to that channel");
+		return ExitCode::FAILURE;
+	}
+	for result in &results {
+		println!("{}: {}", result.computer, if result.success { "ok" } else { "failed" });
+		for line in result.output.lines() {
+			println!("  {}", line);
+		}
+	}
+	if results.iter().all(|e| e.success) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+// just enough http for talking to our own server
+async fn post(address: SocketAddr, path: &str, body: &str, token: &Option<String>) -> std::io::Result<String> {
+	let mut stream = TcpStream::connect(address).await?;
+	let authorization = token.as_ref().map(|e| format!("Authorization: Bearer {}\r\n", e)).unwrap_or_default();
+	let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}", path, address, body.len(), authorization, body);
+	stream.write_all(request.as_bytes()).await?;
+	let mut response = Vec::new();
+	stream.read_to_end(&mut response).await?;
+	let response = String::from_utf8_lossy(&response);
+	Ok(response.split_once("\r\n\r\n").map(|e| e.1).unwrap_or_default().to_string())
+}
+
+fn list(args: ConfigArgs) -> ExitCode {
+	let Some((project, _)) = load(&args, &ProjectOverrides::default()) else {
+		return ExitCode::FAILURE;
+	};
+	let items = project.items.clone();
+	let project = Arc::new(RwLock::new(project));
+	for item in items {
+		println!("{} - {}", item.channel_name, item.item_type.to_string());
+		let required: Vec<String> = get_full_channel_list(vec![item.channel_name.clone()], &project, &mut Default::default()).into_iter()
+			.map(|e| e.channel_name)
+			.filter(|e| *e != item.channel_name)
+			.collect();
+		if !required.is_empty() {
+			println!("  requires {}", required.join(", "));
+		}
+	}
+	ExitCode::SUCCESS
+}
+
+fn init(args: ConfigArgs) -> ExitCode {
+	if args.config.exists() {
+		println!("{} already exists", args.config.to_string_lossy());
+		return ExitCode::FAILURE;
+	}
+	let dir = args.config.parent().map(|e| e.to_path_buf()).unwrap_or_default();
+	let result = std::fs::create_dir_all(dir.join("src/lib")).and_then(|_| std::fs::write(&args.config, PROJECT_TEMPLATE));
+	if let Err(e) = result {
+		println!("failed to create {}: {}", args.config.to_string_lossy(), e);
+		return ExitCode::FAILURE;
+	}
+	println!("created {}, files in src/lib are synced through the \"lib\" channel", args.config.to_string_lossy());
+	ExitCode::SUCCESS
+}
@@ -0,0 +1,107 @@
+use std::{env::current_dir, net::{IpAddr, Ipv4Addr}, path::Path};
+
+use ron::Options;
+
+use crate::structs::Project;
+
+// values given on the command line, they're applied every time project.ron is loaded so they survive reloads
+#[derive(Clone, Debug, Default)]
+pub struct ProjectOverrides {
+	pub port: Option<u16>,
+	pub root_dir: Option<String>,
+	pub bind_address: Option<Vec<String>>
+}
+
+pub fn load_project(cfg_path: &Path, overrides: &ProjectOverrides) -> Result<Project, Vec<String>> {
+	let options = Options::default()
+		.with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+
+	let contents = std::fs::read(cfg_path).map_err(|e| vec![format!("failed to read {}: {}", cfg_path.to_string_lossy(), e)])?;
+
+	let mut project = options.from_bytes::<Project>(&contents).map_err(|e| vec![format!("failed to deserialize {}: {}", cfg_path.to_string_lossy(), e)])?;
+
+	if let Some(port) = overrides.port {
+		project.port = port;
+	}
+	if let Some(root_dir) = &overrides.root_dir {
+		project.root_dir = root_dir.clone();
+	}
+	if let Some(bind_address) = &overrides.bind_address {
+		project.bind_address = Some(bind_address.clone());
+	}
+
+	validate_project(&project)?;
+
+	Ok(project)
+}
+
+pub fn validate_project(project: &Project) -> Result<(), Vec<String>> {
+	let mut errors = Vec::new();
+
+	let root_dir = current_dir().unwrap().join(&project.root_dir);
+
+	if !std::fs::exists(&root_dir).unwrap_or(false) {
+		errors.push(format!("could not find {} relative to current directory", root_dir.to_string_lossy()));
+	}
+
+	if let Err(bind_errors) = get_bind_addresses(&project.bind_address) {
+		errors.extend(bind_errors);
+	}
+
+	if let Some(token) = &project.auth_token && !is_valid_token(token) {
+		errors.push("auth_token can only contain letters, numbers, - and _".to_string());
+	}
+
+	// polling without a pause never lets go of the cpu, and without a window every write of a save gets synced on its own
+	if project.poll_interval_ms == 0 {
+		errors.push("poll_interval_ms has to be at least 1".to_string());
+	}
+	if project.debounce_ms == 0 {
+		errors.push("debounce_ms has to be at least 1".to_string());
+	}
+
+	for item in &project.items {
+		if item.channel_name.contains(char::is_whitespace) {
+			errors.push(format!("channel name \"{}\" contains whitespace, remove any whitespace present", item.channel_name));
+		}
+		if let Some(token) = &item.auth_token && !is_valid_token(token) {
+			errors.push(format!("auth_token of channel \"{}\" can only contain letters, numbers, - and _", item.channel_name));
+		}
+	}
+
+	if errors.is_empty() {
+		Ok(())
+	}
+	else {
+		Err(errors)
+	}
+}
+
+// tokens end up in urls and lua strings in the /download script as is
+fn is_valid_token(token: &str) -> bool {
+	!token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// ipv6 addresses can be written with or without the brackets they need in urls
+pub fn get_bind_addresses(bind_address: &Option<Vec<String>>) -> Result<Vec<IpAddr>, Vec<String>> {
+	let Some(addresses) = bind_address else {
+		return Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+	};
+	if addresses.is_empty() {
+		return Err(vec!["bind_address is empty, remove it to listen on 127.0.0.1".to_string()]);
+	}
+	let mut errors = Vec::new();
+	let mut parsed = Vec::new();
+	for address in addresses {
+		match address.trim_start_matches("[").trim_end_matches("]").parse::<IpAddr>() {
+			Ok(ip) => parsed.push(ip),
+			Err(_) => errors.push(format!("bind address \"{}\" is not an ip address", address))
+		}
+	}
+	if errors.is_empty() {
+		Ok(parsed)
+	}
+	else {
+		Err(errors)
+	}
+}
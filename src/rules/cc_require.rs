@@ -0,0 +1,139 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use darklua_core::{nodes::{Arguments, Block, Expression, FunctionCall, Prefix}, process::{DefaultVisitor, NodeProcessor, NodeVisitor}, rules::{Context, Rule, RuleConfiguration, RuleProcessResult, RuleProperties}};
+use parking_lot::Mutex;
+
+// package.path of a program ran from the shell, without the /rom/modules entries since those are never in the project
+const PACKAGE_PATH: [&str; 3] = ["?", "?.lua", "?/init.lua"];
+
+pub const BUNDLE_REQUIRE: &str = "__ccsync_require";
+
+#[derive(Clone, Debug)]
+pub struct ResolvedModule {
+	pub name: String,
+	pub cc_path: String,
+	pub path: PathBuf
+}
+
+#[derive(Debug, Default)]
+pub struct RequireState {
+	pub resolved: Vec<ResolvedModule>,
+	// every file that was checked while resolving, creating one of these can change what a require resolves to
+	pub searched: Vec<PathBuf>
+}
+
+// replaces require("name") with __ccsync_require("name") for every module that can be found in the project,
+// anything that can't be found is an error unless it's excluded (rom modules, libraries that exist on the computer)
+#[derive(Debug)]
+pub struct CcRequireRule {
+	root: PathBuf,
+	program_dir: String,
+	exclusions: Vec<String>,
+	state: Arc<Mutex<RequireState>>
+}
+
+struct RequireReplacer<'a> {
+	rule: &'a CcRequireRule,
+	errors: Vec<String>
+}
+
+impl CcRequireRule {
+	pub fn new(root: PathBuf, program_dir: String, exclusions: Vec<String>, state: Arc<Mutex<RequireState>>) -> Self {
+		CcRequireRule { root, program_dir, exclusions, state }
+	}
+}
+
+// same search cc.require does: dots become slashes, each package.path pattern is tried in order,
+// and anything that isn't absolute is relative to the directory of the running program
+pub fn resolve_module(root: &Path, program_dir: &str, name: &str, searched: &mut Vec<PathBuf>) -> Option<(String, PathBuf)> {
+	let name = name.replace(".", "/");
+	for pattern in PACKAGE_PATH {
+		let path = pattern.replace("?", &name);
+		let combined = if path.starts_with("/") { combine("", &path) } else { combine(program_dir, &path) };
+		if let Some(cc_path) = combined && !cc_path.is_empty() {
+			let real = root.join(&cc_path);
+			searched.push(real.clone());
+			if real.is_file() {
+				return Some((cc_path, real));
+			}
+		}
+	}
+	None
+}
+
+// fs.combine, except paths that climb out of the root don't resolve at all
+fn combine(dir: &str, path: &str) -> Option<String> {
+	let mut parts: Vec<&str> = Vec::new();
+	for part in dir.split("/").chain(path.split("/")) {
+		match part {
+			"" | "." => {}
+			".." => {
+				parts.pop()?;
+			}
+			_ => parts.push(part)
+		}
+	}
+	Some(parts.join("/"))
+}
+
+impl NodeProcessor for RequireReplacer<'_> {
+	fn process_function_call(&mut self, call: &mut FunctionCall) {
+		let is_require = matches!(call.get_prefix(), Prefix::Identifier(id) if id.get_name() == "require");
+		if call.has_method() || !is_require {
+			return;
+		}
+		let name = match call.get_arguments() {
+			Arguments::String(string) => string.get_string_value(),
+			Arguments::Tuple(tuple) => match tuple.iter_values().next() {
+				Some(Expression::String(string)) => string.get_string_value(),
+				_ => None
+			},
+			_ => None
+		};
+		// requires with a computed name can only be done at runtime
+		let Some(name) = name.map(|e| e.to_string()) else {
+			return;
+		};
+		let mut state = self.rule.state.lock();
+		match resolve_module(&self.rule.root, &self.rule.program_dir, &name, &mut state.searched) {
+			Some((cc_path, path)) => {
+				if let Prefix::Identifier(id) = call.mutate_prefix() {
+					id.set_name(BUNDLE_REQUIRE);
+				}
+				state.resolved.push(ResolvedModule { name, cc_path, path });
+			}
+			None => {
+				if !self.rule.exclusions.contains(&name) {
+					self.errors.push(format!("module '{}' not found (searched {})", name, PACKAGE_PATH.join(";")));
+				}
+			}
+		}
+	}
+}
+
+impl RuleConfiguration for CcRequireRule {
+	fn configure(&mut self, _: RuleProperties) -> Result<(), darklua_core::rules::RuleConfigurationError> {
+		Ok(())
+	}
+
+	fn get_name(&self) -> &'static str {
+		"cc-require"
+	}
+
+	fn serialize_to_properties(&self) -> RuleProperties {
+		RuleProperties::new()
+	}
+}
+
+impl Rule for CcRequireRule {
+	fn process(&self, block: &mut Block, _: &Context) -> RuleProcessResult {
+		let mut processor = RequireReplacer { rule: self, errors: Vec::new() };
+		DefaultVisitor::visit_block(block, &mut processor);
+		if processor.errors.is_empty() {
+			Ok(())
+		}
+		else {
+			Err(processor.errors.join(", "))
+		}
+	}
+}
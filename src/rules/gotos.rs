@@ -0,0 +1,147 @@
+use lazy_regex::regex_replace_all;
+
+// darklua parses luau, which has no goto or labels (and uses :: for type casts), so before anything is handed to it
+// every goto/label statement is swapped for a call to a marker global that no source can contain, and swapped back afterwards.
+// calls are plain statements to darklua, so they survive renaming, space removal and paren removal as long as the name and string do
+const GOTO_MARKER: &str = "__CCSYNC_GOTO";
+const LABEL_MARKER: &str = "__CCSYNC_LABEL";
+
+pub fn encode_gotos(source: &str) -> Result<String, String> {
+	if source.contains(GOTO_MARKER) || source.contains(LABEL_MARKER) {
+		return Err(format!("source contains {} or {}, which are reserved for goto support", GOTO_MARKER, LABEL_MARKER));
+	}
+	let bytes = source.as_bytes();
+	let mut res = String::with_capacity(source.len());
+	// start of the source that hasn't been copied to res yet
+	let mut copied = 0;
+	let mut i = 0;
+	while i < bytes.len() {
+		let c = bytes[i];
+		if c == b'-' && bytes.get(i + 1) == Some(&b'-') {
+			i = skip_comment(bytes, i);
+		}
+		else if c == b'"' || c == b'\'' {
+			i = skip_short_string(bytes, i);
+		}
+		else if c == b'[' && long_bracket_level(bytes, i).is_some() {
+			i = skip_long_bracket(bytes, i);
+		}
+		else if c == b':' && bytes.get(i + 1) == Some(&b':') {
+			let after_colons = skip_whitespace(bytes, i + 2);
+			let name_end = skip_name(bytes, after_colons);
+			let closing = skip_whitespace(bytes, name_end);
+			if name_end > after_colons && bytes.get(closing) == Some(&b':') && bytes.get(closing + 1) == Some(&b':') {
+				res.push_str(&source[copied..i]);
+				res.push_str(&format!("{}(\"{}\")", LABEL_MARKER, &source[after_colons..name_end]));
+				push_newlines(&mut res, &source[i..closing]);
+				i = closing + 2;
+				copied = i;
+			}
+			else {
+				i += 2;
+			}
+		}
+		else if c.is_ascii_alphabetic() || c == b'_' {
+			let end = skip_name(bytes, i);
+			if &source[i..end] == "goto" {
+				let name_start = skip_whitespace(bytes, end);
+				let name_end = skip_name(bytes, name_start);
+				if name_end > name_start && !bytes[name_start].is_ascii_digit() {
+					res.push_str(&source[copied..i]);
+					res.push_str(&format!("{}(\"{}\")", GOTO_MARKER, &source[name_start..name_end]));
+					push_newlines(&mut res, &source[end..name_start]);
+					copied = name_end;
+					i = name_end;
+					continue;
+				}
+			}
+			i = end;
+		}
+		else if c.is_ascii_digit() {
+			// numbers can contain letters (0x1f, 1e5) that must not be read as names
+			i = skip_name(bytes, i);
+		}
+		else {
+			i += 1;
+		}
+	}
+	res.push_str(&source[copied..]);
+	Ok(res)
+}
+
+pub fn decode_gotos(source: &str) -> String {
+	// depending on the rules ran the markers may have lost their parens or gained a semicolon, none of that matters to lua
+	let gotos = regex_replace_all!(r#"__CCSYNC_GOTO\s*\(?\s*["']([A-Za-z_][A-Za-z0-9_]*)["']\s*\)?"#, source, |_, name| format!(" goto {} ", name));
+	let labels = regex_replace_all!(r#"__CCSYNC_LABEL\s*\(?\s*["']([A-Za-z_][A-Za-z0-9_]*)["']\s*\)?"#, &gotos, |_, name| format!(" ::{}:: ", name));
+	labels.to_string()
+}
+
+// keeps line numbers the same when a goto or label was split across lines
+fn push_newlines(res: &mut String, skipped: &str) {
+	for _ in skipped.matches('\n') {
+		res.push('\n');
+	}
+}
+
+fn skip_name(bytes: &[u8], mut i: usize) -> usize {
+	while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+		i += 1;
+	}
+	i
+}
+
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+	while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+		i += 1;
+	}
+	i
+}
+
+fn skip_comment(bytes: &[u8], i: usize) -> usize {
+	if long_bracket_level(bytes, i + 2).is_some() {
+		return skip_long_bracket(bytes, i + 2);
+	}
+	let mut i = i + 2;
+	while i < bytes.len() && bytes[i] != b'\n' {
+		i += 1;
+	}
+	i
+}
+
+fn skip_short_string(bytes: &[u8], i: usize) -> usize {
+	let quote = bytes[i];
+	let mut i = i + 1;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\\' => i += 2,
+			c if c == quote => return i + 1,
+			b'\n' => return i,
+			_ => i += 1
+		}
+	}
+	i
+}
+
+// [[, [=[, [==[ etc, returns the amount of ='s
+fn long_bracket_level(bytes: &[u8], i: usize) -> Option<usize> {
+	if bytes.get(i) != Some(&b'[') {
+		return None;
+	}
+	let mut level = 0;
+	while bytes.get(i + 1 + level) == Some(&b'=') {
+		level += 1;
+	}
+	if bytes.get(i + 1 + level) == Some(&b'[') { Some(level) } else { None }
+}
+
+fn skip_long_bracket(bytes: &[u8], i: usize) -> usize {
+	let level = long_bracket_level(bytes, i).unwrap();
+	let mut i = i + level + 2;
+	while i < bytes.len() {
+		if bytes[i] == b']' && bytes[i + 1..].iter().take(level).all(|e| *e == b'=') && bytes.get(i + 1 + level) == Some(&b']') {
+			return i + level + 2;
+		}
+		i += 1;
+	}
+	i
+}
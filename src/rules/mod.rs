@@ -1 +1,3 @@
-pub mod prefix_requires;
\ No newline at end of file
+pub mod prefix_requires;
+pub mod cc_require;
+pub mod gotos;
\ No newline at end of file
@@ -0,0 +1,89 @@
+use std::{collections::HashSet, sync::Arc};
+
+use ohkami::{FangAction, Request, Response, Status};
+use parking_lot::RwLock;
+
+use crate::{server::util::get_full_channel_list, structs::{Project, ProjectItem}};
+
+// tokens come from the token query parameter, comma separated when channels with different tokens are needed at once,
+// or an `Authorization: Bearer` header. wget on computers can't set headers
+pub fn get_request_tokens(req: &Request) -> Vec<String> {
+	let mut tokens: Vec<String> = req.query.iter()
+		.filter(|(key, _)| key == "token")
+		.flat_map(|(_, value)| value.split(",").map(|e| e.to_string()).collect::<Vec<String>>())
+		.collect();
+	if let Some(token) = req.headers.authorization().and_then(|e| e.strip_prefix("Bearer ")) {
+		tokens.push(token.to_string());
+	}
+	tokens
+}
+
+// compares every byte no matter where the first difference is, so response times don't give away how much of a token was right
+fn same_token(a: &str, b: &str) -> bool {
+	let diff = a.bytes().zip(b.bytes()).fold(a.len() ^ b.len(), |acc, (x, y)| acc | (x ^ y) as usize);
+	diff == 0
+}
+
+fn has_token(tokens: &[String], token: &str) -> bool {
+	tokens.iter().fold(false, |found, e| found | same_token(e, token))
+}
+
+// the project's token is needed for everything, channels with their own token need that one too
+pub fn check_tokens(project: &Project, tokens: &[String], channels: &[ProjectItem]) -> Result<(), String> {
+	if let Some(token) = &project.auth_token && !has_token(tokens, token) {
+		return Err("missing or wrong token".to_string());
+	}
+	let locked: Vec<&str> = channels.iter()
+		.filter(|e| e.auth_token.as_ref().is_some_and(|token| !has_token(tokens, token)))
+		.map(|e| e.channel_name.as_str())
+		.collect();
+	if locked.is_empty() {
+		Ok(())
+	}
+	else {
+		Err(format!("missing or wrong token for {}", locked.join(", ")))
+	}
+}
+
+// whether what computers subscribed to channels send (logs, stats) can be shown to a request with these tokens
+pub fn can_see_channels(project: &Arc<RwLock<Project>>, tokens: &[String], channels: &[String]) -> bool {
+	let included = get_full_channel_list(channels.to_vec(), project, &mut HashSet::new());
+	check_tokens(&project.read(), tokens, &included).is_ok()
+}
+
+// only tokens the project actually uses end up in generated scripts
+pub fn get_known_tokens(project: &Project, tokens: &[String]) -> Vec<String> {
+	tokens.iter()
+		.filter(|token| project.auth_token.iter().chain(project.items.iter().filter_map(|e| e.auth_token.as_ref())).any(|e| same_token(e, token)))
+		.cloned()
+		.collect()
+}
+
+pub fn refuse(req: &Request, reason: &str) -> Response {
+	println!("refused {} from {}: {}", req.path.str(), req.ip, reason);
+	Response::new(Status::Unauthorized).with_payload("text/plain", reason.as_bytes().to_vec())
+}
+
+// admin_token always has to match when it's set. the peer address alone can't be trusted since tunnels
+// connect from localhost, so without a token only allow_local_commands lets this machine in
+pub fn is_admin(req: &Request, project: &Project) -> bool {
+	match &project.admin_token {
+		Some(token) => req.headers.authorization().and_then(|e| e.strip_prefix("Bearer ")).is_some_and(|e| same_token(e, token)),
+		None => project.allow_local_commands && req.ip.is_loopback()
+	}
+}
+
+// checks the project's token before every route
+#[derive(Clone)]
+pub struct ProjectToken(pub Arc<RwLock<Project>>);
+
+impl FangAction for ProjectToken {
+	async fn fore<'a>(&'a self, req: &'a mut Request) -> Result<(), Response> {
+		// subscriptions check tokens themselves so computers get a close reason
+		if req.path.str() == "/subscribe" {
+			return Ok(());
+		}
+		let result = check_tokens(&self.0.read(), &get_request_tokens(req), &[]);
+		result.map_err(|reason| refuse(req, &reason))
+	}
+}
@@ -0,0 +1,130 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, OnceLock, atomic::{AtomicU64, Ordering}}};
+
+use parking_lot::Mutex;
+use rapidhash::v3::rapidhash_v3;
+
+use crate::{server::util::process_file, structs::ProjectItemType};
+
+// everything that changes what process_file produces for a given file
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ProcessOptions {
+	pub item_type: ProjectItemType,
+	pub minify: bool,
+	pub deflate: bool,
+	pub bundle: bool,
+	pub require_prefix: Option<String>,
+	pub prefix_exclusions: Option<Vec<String>>
+}
+
+type CacheEntries = HashMap<(ProcessOptions, u64), Arc<OnceLock<Result<Vec<u8>, Vec<String>>>>>;
+
+// processed output shared by every subscription, so a file is only ran through darklua once per change
+// regardless of how many computers are subscribed to it
+pub struct ProcessedCache {
+	entries: Mutex<HashMap<PathBuf, CacheEntries>>,
+	// files inlined by the last bundle of each entry point, used to rebuild bundles when a required module changes
+	dependencies: Mutex<HashMap<(PathBuf, ProcessOptions), Vec<PathBuf>>>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+	failures: AtomicU64
+}
+
+impl ProcessedCache {
+	pub fn new() -> Self {
+		Self {
+			entries: Mutex::new(HashMap::new()),
+			dependencies: Mutex::new(HashMap::new()),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+			failures: AtomicU64::new(0)
+		}
+	}
+
+	// failures are cached too, they only get reported once and are retried when the file or its dependencies change
+	pub fn get_or_process(&self, path: &Path, root: &Path, options: &ProcessOptions) -> Result<Vec<u8>, Vec<String>> {
+		let content_hash = self.content_hash(path, options);
+		let cell = {
+			let mut entries = self.entries.lock();
+			let for_path = entries.entry(path.to_path_buf()).or_default();
+			// anything processed from an older version of the file (or its dependencies) can't be hit again
+			for_path.retain(|(entry_options, hash), _| entry_options != options || *hash == content_hash);
+			for_path.entry((options.clone(), content_hash)).or_default().clone()
+		};
+		let mut processed = None;
+		let content = cell.get_or_init(|| {
+			let file = process_file(path, root, options);
+			processed = Some(file.dependencies);
+			file.content
+		});
+		if let Some(dependencies) = processed {
+			self.misses.fetch_add(1, Ordering::Relaxed);
+			if let Err(errors) = content {
+				self.failures.fetch_add(1, Ordering::Relaxed);
+				println!("failed to process {}", path.to_string_lossy());
+				for error in errors {
+					println!("  {}", error);
+				}
+			}
+			if options.bundle {
+				// the key above was computed before the dependencies were known, so file the output under one that includes them
+				self.dependencies.lock().insert((path.to_path_buf(), options.clone()), dependencies);
+				let bundle_hash = self.content_hash(path, options);
+				let mut entries = self.entries.lock();
+				let for_path = entries.entry(path.to_path_buf()).or_default();
+				if let Some(cell) = for_path.remove(&(options.clone(), content_hash)) {
+					for_path.insert((options.clone(), bundle_hash), cell);
+				}
+			}
+		}
+		else {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+		}
+		content.clone()
+	}
+
+	// bundles also have to miss when any module they inlined changed, not only the entry point
+	fn content_hash(&self, path: &Path, options: &ProcessOptions) -> u64 {
+		let file_hash = std::fs::read(path).map(|e| rapidhash_v3(&e)).unwrap_or(0);
+		if !options.bundle {
+			return file_hash;
+		}
+		let dependencies = self.dependencies.lock().get(&(path.to_path_buf(), options.clone())).cloned().unwrap_or_default();
+		let mut hashes = file_hash.to_le_bytes().to_vec();
+		for dependency in dependencies {
+			let dependency_hash = std::fs::read(&dependency).map(|e| rapidhash_v3(&e)).unwrap_or(0);
+			hashes.extend_from_slice(dependency.to_string_lossy().as_bytes());
+			hashes.extend_from_slice(&dependency_hash.to_le_bytes());
+		}
+		rapidhash_v3(&hashes)
+	}
+
+	// entry points whose last bundle inlined the given file
+	pub fn dependents(&self, path: &Path) -> Vec<PathBuf> {
+		let dependents: HashSet<PathBuf> = self.dependencies.lock().iter()
+			.filter(|(_, dependencies)| dependencies.iter().any(|e| e == path))
+			.map(|((bundle, _), _)| bundle.clone())
+			.collect();
+		dependents.into_iter().collect()
+	}
+
+	pub fn is_dependency(&self, path: &Path) -> bool {
+		self.dependencies.lock().values().any(|dependencies| dependencies.iter().any(|e| e == path))
+	}
+
+	pub fn invalidate(&self, path: &Path) {
+		self.entries.lock().remove(path);
+		self.dependencies.lock().retain(|(bundle, _), _| bundle != path);
+	}
+
+	pub fn hits(&self) -> u64 {
+		self.hits.load(Ordering::Relaxed)
+	}
+
+	pub fn misses(&self) -> u64 {
+		self.misses.load(Ordering::Relaxed)
+	}
+
+	pub fn failures(&self) -> u64 {
+		self.failures.load(Ordering::Relaxed)
+	}
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, sync::{Arc, atomic::{AtomicU64, Ordering}}};
+
+use parking_lot::Mutex;
+use tokio::sync::{mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel}, oneshot};
+
+use crate::structs::RemoteCommand;
+
+// whether the command succeeded and everything it wrote
+pub type CommandOutput = (bool, String);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientState {
+	// both come from the client_info message, older clients never send it
+	pub computer_id: Option<u32>,
+	pub label: Option<String>,
+	pub channels: Vec<String>,
+	// files the computer confirmed it has written
+	pub acknowledged: u64
+}
+
+impl ClientState {
+	// how the computer shows up in anything printed about it
+	pub fn describe(&self, connection: u64) -> String {
+		match (self.computer_id, &self.label) {
+			(Some(id), Some(label)) => format!("computer {} ({})", id, label),
+			(Some(id), None) => format!("computer {}", id),
+			_ => format!("connection {}", connection)
+		}
+	}
+
+	// the same without spaces, for file names
+	pub fn file_tag(&self, connection: u64) -> String {
+		match self.computer_id {
+			Some(id) => format!("computer-{}", id),
+			None => format!("connection-{}", connection)
+		}
+	}
+}
+
+// every open subscription, keyed by a per-connection id since computers don't have to identify themselves
+pub struct ConnectedClients {
+	next_id: AtomicU64,
+	clients: Mutex<HashMap<u64, ClientState>>,
+	// commands go to the subscription's task, which forwards them to the computer
+	commands: Mutex<HashMap<u64, UnboundedSender<(u64, RemoteCommand)>>>,
+	next_command: AtomicU64,
+	// commands that were sent and haven't been answered yet, along with the connection they were sent to
+	results: Mutex<HashMap<u64, (u64, oneshot::Sender<CommandOutput>)>>
+}
+
+impl ConnectedClients {
+	pub fn new() -> Self {
+		Self {
+			next_id: AtomicU64::new(1),
+			clients: Mutex::new(HashMap::new()),
+			commands: Mutex::new(HashMap::new()),
+			next_command: AtomicU64::new(1),
+			results: Mutex::new(HashMap::new())
+		}
+	}
+
+	// the client is removed once the returned guard is dropped, however the subscription ends
+	pub fn connect(self: &Arc<Self>, channels: Vec<String>) -> (ClientGuard, UnboundedReceiver<(u64, RemoteCommand)>) {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let (sender, receiver) = unbounded_channel();
+		self.clients.lock().insert(id, ClientState { channels, ..Default::default() });
+		self.commands.lock().insert(id, sender);
+		(ClientGuard { id, clients: self.clone() }, receiver)
+	}
+
+	// sends the command to every computer subscribed to channel (or the one with the given id),
+	// the receivers get the result once the computer answers and are dropped if it disconnects first
+	pub fn send_command(&self, channel: &str, computer: Option<u32>, command: &RemoteCommand) -> Vec<(u64, ClientState, oneshot::Receiver<CommandOutput>)> {
+		let targets: Vec<(u64, ClientState)> = self.list().into_iter()
+			.filter(|(_, client)| client.channels.iter().any(|e| e == channel))
+			.filter(|(_, client)| computer.is_none() || client.computer_id == computer)
+			.collect();
+		let mut sent = Vec::new();
+		for (connection, client) in targets {
+			let id = self.next_command.fetch_add(1, Ordering::Relaxed);
+			let (sender, receiver) = oneshot::channel();
+			self.results.lock().insert(id, (connection, sender));
+			let delivered = self.commands.lock().get(&connection).is_some_and(|e| e.send((id, command.clone())).is_ok());
+			if delivered {
+				sent.push((connection, client, receiver));
+			}
+			else {
+				self.results.lock().remove(&id);
+			}
+		}
+		sent
+	}
+
+	// only the computer a command was sent to can answer it
+	pub fn finish_command(&self, connection: u64, id: u64, success: bool, output: String) {
+		let mut results = self.results.lock();
+		if results.get(&id).is_some_and(|e| e.0 == connection) {
+			let _ = results.remove(&id).unwrap().1.send((success, output));
+		}
+	}
+
+	pub fn get(&self, id: u64) -> Option<ClientState> {
+		self.clients.lock().get(&id).cloned()
+	}
+
+	pub fn update(&self, id: u64, f: impl FnOnce(&mut ClientState)) {
+		if let Some(client) = self.clients.lock().get_mut(&id) {
+			f(client);
+		}
+	}
+
+	pub fn list(&self) -> Vec<(u64, ClientState)> {
+		let mut clients: Vec<(u64, ClientState)> = self.clients.lock().iter().map(|(id, client)| (*id, client.clone())).collect();
+		clients.sort_by_key(|e| e.0);
+		clients
+	}
+}
+
+pub struct ClientGuard {
+	pub id: u64,
+	clients: Arc<ConnectedClients>
+}
+
+impl Drop for ClientGuard {
+	fn drop(&mut self) {
+		self.clients.clients.lock().remove(&self.id);
+		self.clients.commands.lock().remove(&self.id);
+		// anything waiting on this computer gets an error instead of waiting until it times out
+		self.clients.results.lock().retain(|_, (connection, _)| *connection != self.id);
+	}
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashSet, env::current_dir, path::PathBuf, sync::Arc};
+
+use notify::{EventHandler, Watcher};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::broadcast::Sender;
+
+use crate::{config::{ProjectOverrides, load_project}, server::{file_watcher::FileChanged, util::{get_all_files, get_changed_channels}}, structs::Project};
+
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct ConfigWatcher {
+	config_path: PathBuf,
+	overrides: ProjectOverrides,
+	project: Arc<RwLock<Project>>,
+	sender: Arc<Sender<FileChanged>>,
+	known_files: Arc<RwLock<HashSet<PathBuf>>>,
+	root_watcher: Arc<Mutex<Box<dyn Watcher + Send>>>
+}
+
+impl ConfigWatcher {
+	#[allow(dead_code)]
+	pub fn new(config_path: PathBuf, overrides: ProjectOverrides, project: Arc<RwLock<Project>>, sender: Arc<Sender<FileChanged>>, known_files: Arc<RwLock<HashSet<PathBuf>>>, root_watcher: Arc<Mutex<Box<dyn Watcher + Send>>>) -> Self {
+		Self {
+			config_path,
+			overrides,
+			project,
+			sender,
+			known_files,
+			root_watcher
+		}
+	}
+
+	fn reload(&self) {
+		let new_project = match load_project(&self.config_path, &self.overrides) {
+			Ok(project) => project,
+			Err(errors) => {
+				println!("{} changed but is invalid, keeping previous config", self.config_path.to_string_lossy());
+				for error in errors {
+					println!("  {}", error);
+				}
+				return;
+			}
+		};
+		let old_project = self.project.read().clone();
+		if old_project == new_project {
+			return;
+		}
+		if old_project.port != new_project.port {
+			println!("port changed from {} to {}, restart the server for it to take effect", old_project.port, new_project.port);
+		}
+		if old_project.bind_address != new_project.bind_address {
+			println!("bind_address changed, restart the server for it to take effect");
+		}
+		if old_project.watch_mode != new_project.watch_mode || old_project.poll_interval_ms != new_project.poll_interval_ms {
+			println!("watch_mode or poll_interval_ms changed, restart the server for it to take effect");
+		}
+		let changed_channels = get_changed_channels(&old_project, &new_project);
+		let old_root = current_dir().unwrap().join(&old_project.root_dir);
+		let new_root = current_dir().unwrap().join(&new_project.root_dir);
+		let files: HashSet<PathBuf> = get_all_files(&new_project).into_iter().collect();
+		*self.project.write() = new_project;
+		if old_root != new_root {
+			let mut watcher = self.root_watcher.lock();
+			let _ = watcher.unwatch(&old_root);
+			if let Err(e) = watcher.watch(&new_root, notify::RecursiveMode::Recursive) {
+				println!("failed to watch {}: {}", new_root.to_string_lossy(), e);
+			}
+		}
+		*self.known_files.write() = files;
+		println!("reloaded {}, {} channel(s) changed", self.config_path.to_string_lossy(), changed_channels.len());
+		self.sender.send(FileChanged::ProjectReloaded { changed_channels }).unwrap();
+	}
+}
+
+impl EventHandler for ConfigWatcher {
+	fn handle_event(&mut self, event: notify::Result<notify::Event>) {
+		let Ok(ev) = event else {
+			return;
+		};
+		if matches!(ev.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(_)) && ev.paths.iter().any(|p| p.file_name() == self.config_path.file_name()) {
+			self.reload();
+		}
+	}
+}
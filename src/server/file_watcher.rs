@@ -1,61 +1,259 @@
-use std::{collections::HashSet, path::PathBuf, sync::Arc};
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, Weak, mpsc::{self, Receiver, RecvTimeoutError}}, time::{Duration, Instant}};
 
-use notify::EventHandler;
+use notify::{EventHandler, PollWatcher, RecursiveMode, Watcher};
+use parking_lot::{Mutex, RwLock};
 use tokio::sync::broadcast::Sender;
 
+use crate::{server::cache::ProcessedCache, structs::{Project, WatchMode}};
+
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct FileWatcher {
+	shared: Arc<WatcherState>
+}
+
+struct WatcherState {
 	sender: Arc<Sender<FileChanged>>,
-	known_files: HashSet<PathBuf>
+	known_files: Arc<RwLock<HashSet<PathBuf>>>,
+	cache: Arc<ProcessedCache>,
+	project: Arc<RwLock<Project>>,
+	// paths with events that haven't settled yet
+	pending: Mutex<HashMap<PathBuf, PendingChange>>,
+	// renames in the order they happened, they settle the same way but are sent before any other change
+	renames: Mutex<Vec<PendingRename>>,
+	// tells the settling thread there's something new to wait for
+	wake: mpsc::Sender<()>
+}
+
+struct PendingChange {
+	created: bool,
+	// whether anything besides being renamed happened to the path
+	content: bool,
+	last_event: Instant
+}
+
+struct PendingRename {
+	from: PathBuf,
+	to: PathBuf,
+	time: Instant
 }
 
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum FileChanged {
 	Changed { path: PathBuf },
-	Deleted { path: PathBuf }
+	Deleted { path: PathBuf },
+	// a file or directory that was moved within the project
+	Renamed { from: PathBuf, to: PathBuf },
+	ProjectReloaded { changed_channels: Vec<String> }
 }
 
 impl FileWatcher {
-	pub fn new(sender: Arc<Sender<FileChanged>>, known_files: HashSet<PathBuf>) -> Self {
-		Self {
+	pub fn new(sender: Arc<Sender<FileChanged>>, known_files: Arc<RwLock<HashSet<PathBuf>>>, cache: Arc<ProcessedCache>, project: Arc<RwLock<Project>>) -> Self {
+		let (wake, woken) = mpsc::channel();
+		let shared = Arc::new(WatcherState {
 			sender,
-			known_files
+			known_files,
+			cache,
+			project,
+			pending: Mutex::new(HashMap::new()),
+			renames: Mutex::new(Vec::new()),
+			wake
+		});
+		// the thread only holds a weak reference, so it stops once the watcher (and with it the wake sender) is dropped
+		let weak = Arc::downgrade(&shared);
+		std::thread::spawn(move || settle_loop(weak, woken));
+		Self { shared }
+	}
+}
+
+// handlers are cloned so auto can hand one to the poll watcher after the native one failed
+pub fn create_watcher<H: EventHandler + Clone>(handler: H, path: &Path, recursive: RecursiveMode, mode: WatchMode, poll_interval: Duration) -> notify::Result<Box<dyn Watcher + Send>> {
+	let native = || -> notify::Result<Box<dyn Watcher + Send>> {
+		let mut watcher = notify::recommended_watcher(handler.clone())?;
+		watcher.watch(path, recursive)?;
+		Ok(Box::new(watcher))
+	};
+	// mtimes aren't reliable on the filesystems polling is for, so contents are compared too
+	let poll = || -> notify::Result<Box<dyn Watcher + Send>> {
+		let config = notify::Config::default().with_poll_interval(poll_interval).with_compare_contents(true);
+		let mut watcher = PollWatcher::new(handler.clone(), config)?;
+		watcher.watch(path, recursive)?;
+		Ok(Box::new(watcher))
+	};
+	match mode {
+		WatchMode::Native => native(),
+		WatchMode::Poll => poll(),
+		WatchMode::Auto => native().or_else(|e| {
+			println!("failed to watch {} ({}), polling every {}ms instead", path.to_string_lossy(), e, poll_interval.as_millis());
+			poll()
+		})
+	}
+}
+
+// sleeps until an event comes in or the earliest pending path is due, nothing runs while the project is quiet
+fn settle_loop(state: Weak<WatcherState>, woken: Receiver<()>) {
+	let mut next = None;
+	loop {
+		let result = match next {
+			Some(wait) => woken.recv_timeout(wait),
+			None => woken.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		};
+		if result == Err(RecvTimeoutError::Disconnected) {
+			return;
 		}
+		// a burst of events only needs one look
+		while woken.try_recv().is_ok() {}
+		let Some(state) = state.upgrade() else {
+			return;
+		};
+		next = state.settle();
 	}
 }
 
-impl EventHandler for FileWatcher {
-	fn handle_event(&mut self, event: notify::Result<notify::Event>) {
-		let ev = event.unwrap();
-		match ev.kind {
-			notify::EventKind::Create(_) => {
-				for file in ev.paths {
-					let metadata = std::fs::metadata(&file).unwrap();
-					if metadata.is_file() {
-						self.known_files.insert(file.clone());
-						let changed = FileChanged::Changed { path: file };
-						self.sender.send(changed).unwrap();
-					}
+impl WatcherState {
+	// whatever a path ended up as once it went debounce_ms without events is what gets sent, so bursts of writes
+	// turn into one change and files that were created and deleted again (like editor temp files) into nothing.
+	// returns how long until the next pending path settles
+	fn settle(&self) -> Option<Duration> {
+		let window = Duration::from_millis(self.project.read().debounce_ms);
+		let renames: Vec<PendingRename> = {
+			let mut renames = self.renames.lock();
+			let count = renames.iter().take_while(|e| e.time.elapsed() >= window).count();
+			renames.drain(..count).collect()
+		};
+		for rename in renames {
+			self.settle_rename(rename.from, rename.to);
+		}
+		let mut settled: Vec<(PathBuf, PendingChange)> = {
+			let mut pending = self.pending.lock();
+			let paths: Vec<PathBuf> = pending.iter().filter(|(_, e)| e.last_event.elapsed() >= window).map(|(path, _)| path.clone()).collect();
+			paths.into_iter().map(|path| {
+				let change = pending.remove(&path).unwrap();
+				(path, change)
+			}).collect()
+		};
+		// a directory can settle together with files created in it (polling reports them in the same scan),
+		// those are left to their own entries the same way as ones that are still pending
+		let created_dirs: Vec<PathBuf> = settled.iter().filter(|(path, change)| change.created && path.is_dir()).map(|(path, _)| path.clone()).collect();
+		for (path, change) in settled.iter_mut() {
+			change.created |= created_dirs.iter().any(|e| path != e && path.starts_with(e));
+		}
+		let batch: HashSet<PathBuf> = settled.iter().map(|(path, _)| path.clone()).collect();
+		for (path, change) in settled {
+			if path.is_dir() {
+				// the outermost directory's walk already covers the ones created inside it
+				if change.created && !created_dirs.iter().any(|e| &path != e && path.starts_with(e)) {
+					self.discover(&path, &batch);
 				}
 			}
-			notify::EventKind::Modify(_) => {
-				for file in ev.paths {
-					if self.known_files.contains(&file) {
-						let changed = FileChanged::Changed { path: file };
-						self.sender.send(changed).unwrap();
-					}
+			else if path.is_file() {
+				if change.created {
+					self.known_files.write().insert(path.clone());
+				}
+				// modules that are only ever required by a bundle aren't part of any channel, but their bundles still need rebuilding
+				if self.known_files.read().contains(&path) || self.cache.is_dependency(&path) {
+					let _ = self.sender.send(FileChanged::Changed { path });
 				}
 			}
-			notify::EventKind::Remove(_) => {
-				for file in ev.paths {
-					if self.known_files.remove(&file) {
-						let changed = FileChanged::Deleted { path: file };
-						self.sender.send(changed).unwrap();
+			else {
+				// directories that were deleted or moved out take every file under them along
+				let removed: Vec<PathBuf> = {
+					let mut known_files = self.known_files.write();
+					let removed: Vec<PathBuf> = known_files.iter().filter(|e| e.starts_with(&path)).cloned().collect();
+					for file in &removed {
+						known_files.remove(file);
 					}
+					removed
+				};
+				if removed.is_empty() && self.cache.is_dependency(&path) {
+					let _ = self.sender.send(FileChanged::Deleted { path });
+				}
+				for file in removed {
+					let _ = self.sender.send(FileChanged::Deleted { path: file });
 				}
 			}
-			_ => {}
 		}
+		let oldest_rename = self.renames.lock().first().map(|e| e.time);
+		let oldest_change = self.pending.lock().values().map(|e| e.last_event).min();
+		oldest_rename.into_iter().chain(oldest_change).min().map(|e| window.saturating_sub(e.elapsed()))
 	}
-}
\ No newline at end of file
+
+	// files in a directory that was created or moved in, most of them were written before the directory was being watched
+	// so they never got events of their own. ones that did are left to those
+	fn discover(&self, dir: &Path, settled: &HashSet<PathBuf>) {
+		let files: Vec<PathBuf> = walkdir::WalkDir::new(dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).map(|e| e.into_path()).collect();
+		let mut pending = self.pending.lock();
+		for file in files {
+			if settled.contains(&file) {
+				continue;
+			}
+			if let Some(change) = pending.get_mut(&file) {
+				change.created = true;
+				continue;
+			}
+			self.known_files.write().insert(file.clone());
+			let _ = self.sender.send(FileChanged::Changed { path: file });
+		}
+	}
+
+	// everything known under from is known under to now, renames of files no channel cares about (like an editor's temp file
+	// being moved over the real one) only update the paths and leave the rest to the changes pending on them
+	fn settle_rename(&self, from: PathBuf, to: PathBuf) {
+		let moved: Vec<PathBuf> = {
+			let mut known_files = self.known_files.write();
+			let moved: Vec<PathBuf> = known_files.iter().filter(|e| e.starts_with(&from)).cloned().collect();
+			for path in &moved {
+				known_files.remove(path);
+				known_files.insert(to.join(path.strip_prefix(&from).unwrap()).components().collect());
+			}
+			moved
+		};
+		if !moved.is_empty() || self.cache.is_dependency(&from) {
+			let _ = self.sender.send(FileChanged::Renamed { from, to });
+		}
+	}
+}
+
+impl EventHandler for FileWatcher {
+	fn handle_event(&mut self, event: notify::Result<notify::Event>) {
+		let ev = match event {
+			Ok(ev) => ev,
+			Err(e) => {
+				println!("file watcher error: {}", e);
+				return;
+			}
+		};
+		if !matches!(ev.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(_) | notify::EventKind::Remove(_)) {
+			return;
+		}
+		let now = Instant::now();
+		let mut pending = self.shared.pending.lock();
+		// inotify reports both halves of a rename on their own first, those stay pending for renames that leave or enter the project
+		if let notify::EventKind::Modify(notify::event::ModifyKind::Name(notify::event::RenameMode::Both)) = ev.kind && let [from, to] = ev.paths.as_slice() {
+			let moved: Vec<PathBuf> = pending.keys().filter(|e| e.starts_with(from) || e.starts_with(to)).cloned().collect();
+			let moved: Vec<(PathBuf, PendingChange)> = moved.into_iter().map(|path| {
+				let change = pending.remove(&path).unwrap();
+				(path, change)
+			}).collect();
+			// edits made before the rename still have to be sent for the new path
+			for (path, change) in moved {
+				if change.content && let Ok(rest) = path.strip_prefix(from) {
+					pending.insert(to.join(rest).components().collect(), PendingChange { last_event: now, ..change });
+				}
+			}
+			self.shared.renames.lock().push(PendingRename { from: from.clone(), to: to.clone(), time: now });
+			let _ = self.shared.wake.send(());
+			return;
+		}
+		let renamed = matches!(ev.kind, notify::EventKind::Modify(notify::event::ModifyKind::Name(_)));
+		let created = matches!(ev.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(notify::event::ModifyKind::Name(notify::event::RenameMode::To)));
+		for file in ev.paths {
+			let change = pending.entry(file).or_insert(PendingChange { created: false, content: false, last_event: now });
+			change.created |= created;
+			change.content |= !renamed;
+			change.last_event = now;
+		}
+		let _ = self.shared.wake.send(());
+	}
+}
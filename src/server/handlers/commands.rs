@@ -0,0 +1,33 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::future::join_all;
+use ohkami::{Json, Request, Response, Status, fang::Context};
+use parking_lot::RwLock;
+use tokio::time::timeout;
+
+use crate::{server::{auth::is_admin, clients::ConnectedClients}, structs::{CommandRequest, CommandResult, Project}};
+
+// how long computers get to finish a command before it's reported as timed out, it keeps running on the computer either way
+const COMMAND_TIMEOUT: u64 = 30;
+
+pub async fn handle_command(
+	req: &Request,
+	Context(project): Context<'_, Arc<RwLock<Project>>>,
+	Context(clients): Context<'_, Arc<ConnectedClients>>,
+	Json(request): Json<CommandRequest>
+) -> Response {
+	if !is_admin(req, &project.read()) {
+		println!("refused a command from {}", req.ip);
+		return Response::new(Status::Forbidden).with_payload("text/plain", b"commands need admin_token, or allow_local_commands to be sent from this machine without it".to_vec());
+	}
+	let sent = clients.send_command(&request.channel, request.computer, &request.command);
+	let results = join_all(sent.into_iter().map(|(connection, client, result)| async move {
+		let (success, output) = match timeout(Duration::from_secs(COMMAND_TIMEOUT), result).await {
+			Ok(Ok(result)) => result,
+			Ok(Err(_)) => (false, "disconnected before answering".to_string()),
+			Err(_) => (false, "timed out".to_string())
+		};
+		CommandResult { computer: client.describe(connection), computer_id: client.computer_id, success, output }
+	})).await;
+	Response::new(Status::OK).with_payload("application/json", serde_json::to_vec(&results).unwrap())
+}
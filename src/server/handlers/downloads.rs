@@ -1,11 +1,33 @@
-use std::sync::Arc;
-use ohkami::{Request, Response, Status, fang::Context};
+use std::{collections::HashSet, sync::Arc};
+use ohkami::{Query, Request, Response, Status, fang::Context};
 use parking_lot::RwLock;
-use crate::{files::{B85_MINIFIED, BASE_B85, BASE_LIBDEFLATE, BASE_LZ4, BASE_SYNC_BUNDLED, LIBDEFLATE_MINIFIED, LZ4_MINIFIED, SYNC_BUNDLED}, structs::Project};
+use serde::Deserialize;
+use crate::{server::{auth::{check_tokens, get_known_tokens, get_request_tokens, refuse}, cache::ProcessedCache, installer::{InstallerCompression, build_installer}, util::{get_channel_output, get_full_channel_list}}, files::{B85_MINIFIED, BASE_B85, BASE_LIBDEFLATE, BASE_LZ4, BASE_SYNC_BUNDLED, LIBDEFLATE_MINIFIED, LZ4_MINIFIED, SYNC_BUNDLED}, structs::Project};
 
-pub async fn handle_download(req: &Request) -> ohkami::Response {
-	if let Some(host) = req.headers.host() {
-		let st = format!("local function del(p) if fs.exists(p) then fs.delete(p) end end del(\"/sync.lua\") del(\"/cc-sync/libdeflate.lua\") del(\"/cc-sync/base85.lua\") del(\"/cc-sync/llz4.lua\") shell.run(\"wget http://{0}/sync.lua\")\nshell.run(\"wget http://{0}/libdeflate.lua cc-sync/libdeflate.lua\")\nshell.run(\"wget http://{0}/base85.lua cc-sync/base85.lua\")\nshell.run(\"wget http://{0}/lz4.lua cc-sync/llz4.lua\")", host);
+// the scripts download everything else from whatever address the computer reached the server through,
+// which is the only one that's known to work when listening on several addresses or 0.0.0.0/::
+fn get_request_host(req: &Request) -> Option<&str> {
+	let host = req.headers.host()?;
+	// it ends up inside lua strings, ipv6 hosts keep their brackets
+	let valid = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']'));
+	if valid { Some(host) } else { None }
+}
+
+// the tokens the script was downloaded with are saved for sync.lua and passed along to the other downloads.
+// config validation keeps them to characters that are safe in lua strings and urls
+fn get_token_setup(req: &Request, project: &Project) -> (String, String) {
+	let tokens = get_known_tokens(project, &get_request_tokens(req));
+	if tokens.is_empty() {
+		return (String::new(), String::new());
+	}
+	let tokens = tokens.join(",");
+	(format!("settings.set(\"sync.token\", \"{0}\") settings.save() ", tokens), format!("?token={}", tokens))
+}
+
+pub async fn handle_download(req: &Request, Context(project): Context<'_, Arc<RwLock<Project>>>) -> ohkami::Response {
+	if let Some(host) = get_request_host(req) {
+		let (setup, query) = get_token_setup(req, &project.read());
+		let st = format!("{2}local function del(p) if fs.exists(p) then fs.delete(p) end end del(\"/sync.lua\") del(\"/cc-sync/libdeflate.lua\") del(\"/cc-sync/base85.lua\") del(\"/cc-sync/llz4.lua\") shell.run(\"wget http://{0}/sync.lua{1} sync.lua\")\nshell.run(\"wget http://{0}/libdeflate.lua{1} cc-sync/libdeflate.lua\")\nshell.run(\"wget http://{0}/base85.lua{1} cc-sync/base85.lua\")\nshell.run(\"wget http://{0}/lz4.lua{1} cc-sync/llz4.lua\")", host, query, setup);
 		let bytes: Vec<u8> = st.bytes().collect();
 		Response::new(Status::OK).with_payload("text/plain", bytes)
 	}
@@ -14,9 +36,10 @@ pub async fn handle_download(req: &Request) -> ohkami::Response {
 	}
 }
 
-pub async fn handle_download_nomin(req: &Request) -> ohkami::Response {
-	if let Some(host) = req.headers.host() {
-		let st = format!("local function del(p) if fs.exists(p) then fs.delete(p) end end del(\"/sync.lua\") del(\"/cc-sync/libdeflate.lua\") del(\"/cc-sync/base85.lua\") del(\"/cc-sync/llz4.lua\") shell.run(\"wget http://{0}/base-sync.lua sync.lua\")\nshell.run(\"wget http://{0}/base-libdeflate.lua cc-sync/libdeflate.lua\")\nshell.run(\"wget http://{0}/base-base85.lua cc-sync/base85.lua\")\nshell.run(\"wget http://{0}/base-lz4.lua cc-sync/llz4.lua\")", host);
+pub async fn handle_download_nomin(req: &Request, Context(project): Context<'_, Arc<RwLock<Project>>>) -> ohkami::Response {
+	if let Some(host) = get_request_host(req) {
+		let (setup, query) = get_token_setup(req, &project.read());
+		let st = format!("{2}local function del(p) if fs.exists(p) then fs.delete(p) end end del(\"/sync.lua\") del(\"/cc-sync/libdeflate.lua\") del(\"/cc-sync/base85.lua\") del(\"/cc-sync/llz4.lua\") shell.run(\"wget http://{0}/base-sync.lua{1} sync.lua\")\nshell.run(\"wget http://{0}/base-libdeflate.lua{1} cc-sync/libdeflate.lua\")\nshell.run(\"wget http://{0}/base-base85.lua{1} cc-sync/base85.lua\")\nshell.run(\"wget http://{0}/base-lz4.lua{1} cc-sync/llz4.lua\")", host, query, setup);
 		let bytes: Vec<u8> = st.bytes().collect();
 		Response::new(Status::OK).with_payload("text/plain", bytes)
 	}
@@ -63,4 +86,27 @@ pub async fn handle_download_lz4() -> ohkami::Response {
 
 pub async fn handle_download_base_lz4() -> ohkami::Response {
 	Response::new(Status::OK).with_payload("text/plain", BASE_LZ4.as_bytes())
-}
\ No newline at end of file
+}
+#[derive(Deserialize)]
+pub struct InstallerQuery {
+	channels: String,
+	#[serde(default)]
+	compression: InstallerCompression
+}
+
+pub async fn handle_installer(
+	req: &Request,
+	Context(project): Context<'_, Arc<RwLock<Project>>>,
+	Context(cache): Context<'_, Arc<ProcessedCache>>,
+	Query(query): Query<InstallerQuery>
+) -> ohkami::Response {
+	let channels: Vec<String> = query.channels.split(",").map(|e| e.to_string()).collect();
+	let included = get_full_channel_list(channels.clone(), project, &mut HashSet::new());
+	if let Err(reason) = check_tokens(&project.read(), &get_request_tokens(req), &included) {
+		return refuse(req, &reason);
+	}
+	match get_channel_output(project, &channels, cache) {
+		Ok(files) => Response::new(Status::OK).with_payload("text/plain", build_installer(&files, &channels, query.compression).into_bytes()),
+		Err(errors) => Response::new(Status::BadRequest).with_payload("text/plain", errors.join("\n").into_bytes())
+	}
+}
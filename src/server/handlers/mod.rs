@@ -1,3 +1,4 @@
 pub mod downloads;
 pub mod websocket;
-pub mod root;
\ No newline at end of file
+pub mod root;
+pub mod commands;
\ No newline at end of file
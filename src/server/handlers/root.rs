@@ -1,10 +1,11 @@
 use std::{io::Write, sync::Arc};
 
 use flate2::{Compression, write};
-use ohkami::{Response, Status, fang::Context};
+use ohkami::{Query, Request, Response, Status, fang::Context};
 use parking_lot::RwLock;
+use serde::Deserialize;
 
-use crate::structs::{Project, ProjectItem};
+use crate::{server::{auth::{can_see_channels, get_request_tokens}, cache::ProcessedCache, clients::ConnectedClients, logs::{LogBuffer, LogLine}}, structs::{Project, ProjectItem}};
 
 pub async fn handle_get_root(
 	Context(project): Context<'_, Arc<RwLock<Project>>>
@@ -19,4 +20,47 @@ pub async fn handle_get_root(
 		compressed.write_all("\n".as_bytes()).unwrap();
 	}
 	Response::new(Status::OK).with_payload("application/octet-stream", compressed.finish().unwrap())
-}
\ No newline at end of file
+}
+
+// computers subscribed to channels with their own auth_token only show up with that token
+pub async fn handle_get_stats(
+	req: &Request,
+	Context(project): Context<'_, Arc<RwLock<Project>>>,
+	Context(cache): Context<'_, Arc<ProcessedCache>>,
+	Context(clients): Context<'_, Arc<ConnectedClients>>
+) -> ohkami::Response {
+	let tokens = get_request_tokens(req);
+	let connected: Vec<_> = clients.list().into_iter().filter(|(_, client)| can_see_channels(project, &tokens, &client.channels)).collect();
+	let mut stats = format!("cache hits: {}\ncache misses: {}\nconnected computers: {}\n", cache.hits(), cache.misses(), connected.len());
+	for (id, client) in connected {
+		stats.push_str(&format!("  {} - {}, {} file(s) acknowledged\n", client.describe(id), client.channels.join(", "), client.acknowledged));
+	}
+	Response::new(Status::OK).with_payload("text/plain", stats.into_bytes())
+}
+// defaults to every line that's still kept
+#[derive(Deserialize)]
+pub struct LogsQuery {
+	computer: Option<u32>,
+	channel: Option<String>,
+	lines: Option<usize>
+}
+
+// the same goes for output of computers subscribed to those channels
+pub async fn handle_get_logs(
+	req: &Request,
+	Context(project): Context<'_, Arc<RwLock<Project>>>,
+	Context(logs): Context<'_, Arc<LogBuffer>>,
+	Query(query): Query<LogsQuery>
+) -> ohkami::Response {
+	let tokens = get_request_tokens(req);
+	let mut visible: Vec<LogLine> = logs.get(query.computer, query.channel.as_deref(), usize::MAX).into_iter().filter(|e| can_see_channels(project, &tokens, &e.channels)).collect();
+	let lines = query.lines.unwrap_or(usize::MAX);
+	if visible.len() > lines {
+		visible.drain(..visible.len() - lines);
+	}
+	let mut text = String::new();
+	for line in visible {
+		text.push_str(&format!("{} {}\n", line.time, line.format()));
+	}
+	Response::new(Status::OK).with_payload("text/plain", text.into_bytes())
+}
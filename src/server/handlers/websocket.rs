@@ -1,136 +1,382 @@
-use std::{collections::HashSet, env::current_dir, io::Write, sync::Arc, time::Duration};
-use flate2::{Compression, write::DeflateEncoder};
-use ohkami::{Query, fang::Context, ws::{Message, WebSocket, WebSocketContext}};
+use std::{collections::HashSet, env::current_dir, sync::Arc, time::Duration};
+use futures_util::StreamExt;
+use ohkami::{Query, Request, fang::Context, ws::{CloseCode, WriteHalf, CloseFrame, Message, WebSocket, WebSocketContext}};
 use parking_lot::RwLock;
 use serde::Deserialize;
-use tokio::{sync::{Mutex, broadcast::Sender}, time::interval};
-use crate::{server::{file_watcher::FileChanged, util::{FileBatcher, chunk_batch, get_associated_item, get_files_for_channel, get_full_channel_list, merge, process_tup}}, structs::{Project, ProjectItem, RequestType}};
+use tokio::{io::AsyncWrite, sync::{Mutex, broadcast::Sender, watch}, time::{interval, timeout}};
+use crate::{server::{auth::{check_tokens, get_request_tokens}, cache::ProcessedCache, clients::ConnectedClients, logs::{LogBuffer, LogLine}, file_watcher::FileChanged, util::{FileBatcher, ProjectDefaults, RenameChanges, UploadResult, apply_manifest, apply_upload, check_protocol_version, get_bidirectional_paths, encode_batch, wait_for_shutdown, get_associated_item, get_cc_path, get_cc_paths_for_channels, get_channel_changes, get_rename_changes, find_cc_path, find_cc_paths_under, get_full_channel_list, process_channels, process_tup}}, structs::{ClientMessage, PROTOCOL_VERSION, Project, ProjectItem, RemoteCommand, RequestType, SERVER_CAPABILITIES, ServerMessage}};
+
+const HANDSHAKE_TIMEOUT: u64 = 2;
+// edits that conflict with changes on disk are saved here, next to project.ron rather than under root_dir so they don't get synced
+const CONFLICT_DIR: &str = "sync-conflicts";
+const MANIFEST_TIMEOUT: u64 = 2;
 
 #[derive(Deserialize)]
 pub struct SubscribeQuery {
 	channels: String
 }
 
+// everything subscriptions share with the rest of the server, ohkami handlers can only take so many arguments
+#[derive(Clone)]
+pub struct SubscriptionContext {
+	pub file_changed: Arc<Sender<FileChanged>>,
+	pub cache: Arc<ProcessedCache>,
+	pub shutdown: Arc<watch::Sender<bool>>,
+	pub clients: Arc<ConnectedClients>,
+	pub logs: Arc<LogBuffer>
+}
+
 pub async fn handle_subscribe(
+	req: &Request,
 	Context(project): Context<'_, Arc<RwLock<Project>>>,
-	Context(u_mpsc): Context<'_, Arc<Sender<FileChanged>>>,
+	Context(shared): Context<'_, SubscriptionContext>,
 	ctx: WebSocketContext<'_>,
 	Query(channels): Query<SubscribeQuery>
 ) -> WebSocket {
-	let mut rx = u_mpsc.subscribe();
+	let mut rx = shared.file_changed.subscribe();
+	let mut shutdown_rx = shared.shutdown.subscribe();
 	let arc = project.clone();
+	let cache = shared.cache.clone();
+	let clients = shared.clients.clone();
+	let logs = shared.logs.clone();
 	let p = arc.read();
-	let project_minify = p.minify.clone();
-	let project_deflate = p.deflate_trickery.clone();
-	let project_root = p.root_dir.clone();
-	let project_prefix = p.require_prefix.clone();
-	let project_exclusions = p.prefix_exclusions.clone();
-	let sync_interval = p.sync_interval.clone();
-	let max_size = p.max_uncompressed_request_size.clone();
-	let c: Vec<ProjectItem> = get_full_channel_list(channels.channels.split(",").map(|e| e.to_string()).collect(), &arc, &mut HashSet::new());
+	let mut defaults = ProjectDefaults::new(&p);
+	let mut project_root = p.root_dir.clone();
+	let mut sync_interval = p.sync_interval;
+	let mut max_size = p.max_uncompressed_request_size;
+	let mut requested: Vec<String> = channels.channels.split(",").map(|e| e.to_string()).collect();
+	let c: Vec<ProjectItem> = get_full_channel_list(requested.clone(), &arc, &mut HashSet::new());
+	let tokens = get_request_tokens(req);
+	let refused = check_tokens(&p, &tokens, &c).err();
+	if let Some(reason) = &refused {
+		println!("refused subscription to {} from {}: {}", requested.join(", "), req.ip, reason);
+	}
 	std::mem::drop(p);
 	ctx.upgrade(move |conn| async move {
-		let conn_arc = Arc::new(Mutex::new(conn));
-		let c_list = c;
-		let batcher = Arc::new(Mutex::new(FileBatcher::new()));
-		let root_path = current_dir().unwrap().join(&project_root);
-		for channel in &c_list {
-			let files = get_files_for_channel(&root_path, channel);
-			for file in files {
-				let tup = get_associated_item(&arc, &file, &channel.channel_name);
-				let mut batcher_locked = batcher.lock().await;
-				process_tup(tup, &mut batcher_locked, &project_minify, &project_deflate, &project_prefix, &project_exclusions, &file, &root_path);
+		let (reader, writer) = conn.split();
+		// recv isn't cancel safe, so reading happens inside a stream that keeps its pending read between select! iterations.
+		// the stream ends once the client disconnects
+		let mut messages = Box::pin(futures_util::stream::unfold(reader, |mut reader| async move {
+			loop {
+				match reader.recv().await {
+					Ok(Some(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+						Ok(message) => return Some((message, reader)),
+						Err(e) => println!("ignoring invalid message from client: {}", e)
+					},
+					Ok(Some(Message::Binary(data))) => match ClientMessage::from_binary(&data) {
+						Some(message) => return Some((message, reader)),
+						None => println!("ignoring invalid binary message from client")
+					},
+					Ok(Some(Message::Close(_))) | Ok(None) | Err(_) => return None,
+					Ok(Some(_)) => {}
+				}
 			}
+		}));
+		let mut writer = writer;
+		if let Some(reason) = refused {
+			let frame = CloseFrame { code: CloseCode::Policy, reason: Some(reason.into()) };
+			let _ = writer.send(Message::Close(Some(frame))).await;
+			return;
 		}
-		let batched = batcher.lock().await.retrieve_batch();
-		if batched.len() > 0 {
-			let merged = merge(batched);
-			let chunked = chunk_batch(merged, max_size);
-			let mut lock = conn_arc.lock().await;
-			for chunk in chunked {
-				let byte_vec = {
-					let bytes = {
-						let mut v = Vec::new();
-						for req in chunk {
-							v.extend_from_slice(&<RequestType as Into<Vec<u8>>>::into(req));
-						}
-						v
-					};
-					let mut deflate = DeflateEncoder::new(vec![], Compression::best());
-					deflate.write_all(&bytes).unwrap();
-					deflate.finish().unwrap()
-				};
-				lock.send(Message::Binary(byte_vec)).await.unwrap();
-				lock.flush().await.unwrap();
+		// sync.lua introduces itself before anything else, clients that don't would misparse everything sent to them
+		let version = match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), messages.next()).await {
+			Ok(Some(ClientMessage::Hello { version })) => Some(version),
+			_ => None
+		};
+		if let Err(reason) = check_protocol_version(version) {
+			println!("refusing client: {}", reason);
+			let frame = CloseFrame { code: CloseCode::Policy, reason: Some(reason.into()) };
+			let _ = writer.send(Message::Close(Some(frame))).await;
+			return;
+		}
+		let hello = ServerMessage::Hello { version: PROTOCOL_VERSION, capabilities: SERVER_CAPABILITIES.iter().map(|e| e.to_string()).collect() };
+		if send_message(&mut writer, &hello).await.is_err() {
+			return;
+		}
+		// clients send the files they already have before anything is synced, sync.lua says which computer it is first
+		let mut info = None;
+		let manifest = timeout(Duration::from_secs(MANIFEST_TIMEOUT), async {
+			loop {
+				match messages.next().await {
+					Some(ClientMessage::ClientInfo { id, label }) => info = Some((id, label)),
+					Some(ClientMessage::Manifest { files }) => return Some(files),
+					_ => return None
+				}
 			}
+		}).await.ok().flatten();
+		let (client, mut commands) = clients.connect(requested.clone());
+		if let Some((id, label)) = info {
+			set_client_info(&clients, client.id, id, label, &requested);
+		}
+		let conn_arc = Arc::new(Mutex::new(writer));
+		let mut c_list = c;
+		let batcher = Arc::new(Mutex::new(FileBatcher::new()));
+		let mut root_path = current_dir().unwrap().join(&project_root);
+		let mut batched = process_channels(&arc, &root_path, &c_list, &cache);
+		if let Some(manifest) = manifest {
+			let owned = get_cc_paths_for_channels(&root_path, &arc.read().items);
+			batched = apply_manifest(batched, &manifest, &owned);
+		}
+		if !batched.is_empty() && send_batch(&mut *conn_arc.lock().await, batched, max_size).await.is_err() {
+			return;
+		}
+		let mut bidirectional = get_bidirectional_paths(&arc, &root_path, &c_list);
+		if !bidirectional.is_empty() && send_message(&mut *conn_arc.lock().await, &ServerMessage::Bidirectional { files: bidirectional.clone() }).await.is_err() {
+			return;
 		}
 		let mut interval = interval(Duration::from_secs(sync_interval));
 		loop {
 			tokio::select! {
 				biased;
 
+				_ = wait_for_shutdown(&mut shutdown_rx) => {
+					let frame = CloseFrame { code: CloseCode::Away, reason: Some("server shutting down".into()) };
+					let _ = conn_arc.lock().await.send(Message::Close(Some(frame))).await;
+					break;
+				}
+				message = messages.next() => {
+					let previous = requested.clone();
+					// computers only change their own list once the server says what it accepted
+					let confirm = matches!(message, Some(ClientMessage::Subscribe { .. }) | Some(ClientMessage::Unsubscribe { .. }));
+					match message {
+						Some(ClientMessage::Hello { .. }) | Some(ClientMessage::Manifest { .. }) => {}
+						Some(ClientMessage::Ack { files }) => {
+							clients.update(client.id, |e| e.acknowledged += files.len() as u64);
+						}
+						Some(ClientMessage::Subscribe { channels }) => {
+							for channel in channels {
+								if !arc.read().items.iter().any(|e| e.channel_name == channel) {
+									println!("{} tried to subscribe to {}, which doesn't exist", describe(&clients, client.id), channel);
+									continue;
+								}
+								let included = get_full_channel_list(vec![channel.clone()], &arc, &mut HashSet::new());
+								let check = check_tokens(&arc.read(), &tokens, &included);
+								if let Err(reason) = check {
+									println!("refused subscription to {} from {}: {}", channel, describe(&clients, client.id), reason);
+								}
+								else if !requested.contains(&channel) {
+									requested.push(channel);
+								}
+							}
+						}
+						Some(ClientMessage::Unsubscribe { channels }) => {
+							requested.retain(|e| !channels.contains(e));
+						}
+						Some(ClientMessage::ClientInfo { id, label }) => {
+							set_client_info(&clients, client.id, id, label, &requested);
+						}
+						Some(ClientMessage::Log { line, error }) => {
+							let computer_id = clients.get(client.id).and_then(|e| e.computer_id);
+							logs.push(LogLine::new(computer_id, describe(&clients, client.id), requested.clone(), error, line));
+						}
+						Some(ClientMessage::CommandResult { id, success, output }) => {
+							clients.finish_command(client.id, id, success, output);
+						}
+						Some(ClientMessage::Upload { path, base_hash, content }) => {
+							let who = describe(&clients, client.id);
+							match find_cc_path(&root_path, &c_list, &path) {
+								Some((file, _)) if bidirectional.contains(&path) => {
+									let tag = clients.get(client.id).map(|e| e.file_tag(client.id)).unwrap_or_default();
+									match apply_upload(&file, &path, &content, &base_hash, &current_dir().unwrap().join(CONFLICT_DIR), &tag) {
+										Ok(UploadResult::Written) => println!("{} edited {}", who, path),
+										Ok(UploadResult::Conflict(copy)) => println!("{} edited {}, which changed on disk since, saved the edit to {}", who, path, copy.to_string_lossy()),
+										Err(e) => println!("failed to write {} from {}: {}", path, who, e)
+									}
+								}
+								_ => println!("{} tried to edit {}, which isn't synced as is by a bidirectional channel", who, path)
+							}
+						}
+						// a renamed directory the computer didn't have yet comes back as a resend of the directory
+						Some(ClientMessage::Resend { path }) => {
+							let mut batcher_locked = batcher.lock().await;
+							for (file, channel) in find_cc_paths_under(&root_path, &c_list, &path) {
+								let tup = get_associated_item(&arc, &file, &channel);
+								process_tup(tup, &mut batcher_locked, &cache, &defaults, &file, &root_path);
+							}
+						}
+						None => break
+					}
+					// the files of channels only the new list includes are sent, anything that only belonged to dropped channels is deleted
+					if requested != previous {
+						let new_list = get_full_channel_list(requested.clone(), &arc, &mut HashSet::new());
+						let (removed, files) = get_channel_changes(&root_path, &c_list, &root_path, &new_list, &[]);
+						let mut batcher_locked = batcher.lock().await;
+						if !removed.is_empty() {
+							batcher_locked.add_request(RequestType::Deletion { files: removed });
+						}
+						for (file, channel) in files {
+							let tup = get_associated_item(&arc, &file, &channel);
+							process_tup(tup, &mut batcher_locked, &cache, &defaults, &file, &root_path);
+						}
+						c_list = new_list;
+						if update_bidirectional(&mut *conn_arc.lock().await, &mut bidirectional, get_bidirectional_paths(&arc, &root_path, &c_list)).await.is_err() {
+							break;
+						}
+						clients.update(client.id, |e| e.channels = requested.clone());
+						println!("{} is now subscribed to {}", describe(&clients, client.id), requested.join(", "));
+					}
+					if confirm && send_message(&mut *conn_arc.lock().await, &ServerMessage::Channels { channels: requested.clone() }).await.is_err() {
+						break;
+					}
+				}
+				Some((id, command)) = commands.recv() => {
+					let message = match command {
+						RemoteCommand::Run { program, args } => ServerMessage::Run { id, program, args },
+						RemoteCommand::Reboot => ServerMessage::Reboot { id }
+					};
+					if send_message(&mut *conn_arc.lock().await, &message).await.is_err() {
+						break;
+					}
+				}
 				trnsmit = rx.recv() => {
 					if let Ok(msg) = trnsmit {
 						match msg {
 							FileChanged::Changed { path } => {
-								for channel in &c_list {
-									let tup = get_associated_item(&arc, &path, &channel.channel_name);
-									let mut batcher_locked = batcher.lock().await;
-									process_tup(tup, &mut batcher_locked, &project_minify, &project_deflate, &project_prefix, &project_exclusions, &path, &root_path);
+								let mut changed = vec![path.clone()];
+								changed.extend(cache.dependents(&path));
+								for file in changed {
+									for channel in &c_list {
+										let tup = get_associated_item(&arc, &file, &channel.channel_name);
+										let mut batcher_locked = batcher.lock().await;
+										process_tup(tup, &mut batcher_locked, &cache, &defaults, &file, &root_path);
+									}
+								}
+								// new files, including ones found in created directories, can be edited on computers too
+								if update_bidirectional(&mut *conn_arc.lock().await, &mut bidirectional, get_bidirectional_paths(&arc, &root_path, &c_list)).await.is_err() {
+									break;
 								}
 							}
 							FileChanged::Deleted { path } => {
+								cache.invalidate(&path);
 								for channel in &c_list {
-									let tup = get_associated_item(&arc, &path, &channel.channel_name);
-									if let Some(res) = tup {
-										if let Some(_) = res.0 {
-											let cc_path = path.strip_prefix(&project_root).unwrap();
-											let mut batcher_locked = batcher.lock().await;
-											batcher_locked.add_request(RequestType::Deletion { files: vec![cc_path.to_string_lossy().to_string()] });
-										}
-										else if let Some(file) = res.1 {
-											let cc_path = {
-												if let Some(p) = file.cc_path {
-													p
-												}
-												else {
-													path.strip_prefix(&project_root).unwrap().to_string_lossy().to_string()
-												}
-											};
-											let mut batcher_locked = batcher.lock().await;
-											batcher_locked.add_request(RequestType::Deletion { files: vec![cc_path] });
-										}
+									if get_associated_item(&arc, &path, &channel.channel_name).is_some() {
+										let mut batcher_locked = batcher.lock().await;
+										batcher_locked.add_request(RequestType::Deletion { files: vec![get_cc_path(&root_path, channel, &path)] });
+									}
+								}
+								// bundles that inlined the file get rebuilt, which reports the now missing module
+								for bundle in cache.dependents(&path) {
+									for channel in &c_list {
+										let tup = get_associated_item(&arc, &bundle, &channel.channel_name);
+										let mut batcher_locked = batcher.lock().await;
+										process_tup(tup, &mut batcher_locked, &cache, &defaults, &bundle, &root_path);
 									}
 								}
+								if update_bidirectional(&mut *conn_arc.lock().await, &mut bidirectional, get_bidirectional_paths(&arc, &root_path, &c_list)).await.is_err() {
+									break;
+								}
+							}
+							FileChanged::Renamed { from, to } => {
+								cache.invalidate(&from);
+								let RenameChanges { renames, removed, changed } = get_rename_changes(&arc, &root_path, &c_list, &from, &to);
+								let mut batcher_locked = batcher.lock().await;
+								if !renames.is_empty() {
+									batcher_locked.add_request(RequestType::Rename { renames });
+								}
+								if !removed.is_empty() {
+									batcher_locked.add_request(RequestType::Deletion { files: removed });
+								}
+								for (file, channel) in changed {
+									let tup = get_associated_item(&arc, &file, &channel);
+									process_tup(tup, &mut batcher_locked, &cache, &defaults, &file, &root_path);
+								}
+								// bundles that inlined the file get rebuilt, which reports it missing under its old path
+								for bundle in cache.dependents(&from) {
+									for channel in &c_list {
+										let tup = get_associated_item(&arc, &bundle, &channel.channel_name);
+										process_tup(tup, &mut batcher_locked, &cache, &defaults, &bundle, &root_path);
+									}
+								}
+								std::mem::drop(batcher_locked);
+								if update_bidirectional(&mut *conn_arc.lock().await, &mut bidirectional, get_bidirectional_paths(&arc, &root_path, &c_list)).await.is_err() {
+									break;
+								}
+							}
+							FileChanged::ProjectReloaded { changed_channels } => {
+								let interval_changed = {
+									let p = arc.read();
+									defaults = ProjectDefaults::new(&p);
+									project_root = p.root_dir.clone();
+									max_size = p.max_uncompressed_request_size;
+									let changed = sync_interval != p.sync_interval;
+									sync_interval = p.sync_interval;
+									changed
+								};
+								if interval_changed {
+									interval = tokio::time::interval(Duration::from_secs(sync_interval));
+								}
+								let new_root = current_dir().unwrap().join(&project_root);
+								let new_list = get_full_channel_list(requested.clone(), &arc, &mut HashSet::new());
+								// tokens can change with the config, computers that no longer have the right ones are disconnected
+								let check = check_tokens(&arc.read(), &tokens, &new_list);
+								if let Err(reason) = check {
+									println!("disconnecting {}: {}", describe(&clients, client.id), reason);
+									let frame = CloseFrame { code: CloseCode::Policy, reason: Some(reason.into()) };
+									let _ = conn_arc.lock().await.send(Message::Close(Some(frame))).await;
+									break;
+								}
+								// edited channels get fully resent
+								let (removed, files) = get_channel_changes(&root_path, &c_list, &new_root, &new_list, &changed_channels);
+								let mut batcher_locked = batcher.lock().await;
+								if !removed.is_empty() {
+									batcher_locked.add_request(RequestType::Deletion { files: removed });
+								}
+								for (file, channel) in files {
+									let tup = get_associated_item(&arc, &file, &channel);
+									process_tup(tup, &mut batcher_locked, &cache, &defaults, &file, &new_root);
+								}
+								root_path = new_root;
+								c_list = new_list;
+								std::mem::drop(batcher_locked);
+								if update_bidirectional(&mut *conn_arc.lock().await, &mut bidirectional, get_bidirectional_paths(&arc, &root_path, &c_list)).await.is_err() {
+									break;
+								}
 							}
 						}
 					}
 				}
 				_ = interval.tick() => {
 					let batched = batcher.lock().await.retrieve_batch();
-					if batched.len() > 0 {
-						let merged = merge(batched);
-						let chunked = chunk_batch(merged, max_size);
-						let mut lock = conn_arc.lock().await;
-						for chunk in chunked {
-							let byte_vec = {
-								let bytes = {
-									let mut v = Vec::new();
-									for req in chunk {
-										v.extend_from_slice(&<RequestType as Into<Vec<u8>>>::into(req));
-									}
-									v
-								};
-								let mut deflate = DeflateEncoder::new(vec![], Compression::best());
-								deflate.write_all(&bytes).unwrap();
-								deflate.finish().unwrap()
-							};
-							lock.send(Message::Binary(byte_vec)).await.unwrap();
-							lock.flush().await.unwrap();
-						}
+					if !batched.is_empty() && send_batch(&mut *conn_arc.lock().await, batched, max_size).await.is_err() {
+						break;
 					}
 				}
 			}
 		}
 	})
-}
\ No newline at end of file
+}
+
+// fails once the client is gone, which ends the subscription instead of panicking the task
+async fn send_batch<C: AsyncWrite + Unpin>(conn: &mut WriteHalf<C>, batched: Vec<RequestType>, max_size: usize) -> Result<(), std::io::Error> {
+	for message in encode_batch(batched, max_size) {
+		conn.send(Message::Binary(message)).await?;
+		conn.flush().await?;
+	}
+	Ok(())
+}
+
+fn describe(clients: &ConnectedClients, id: u64) -> String {
+	clients.get(id).map(|e| e.describe(id)).unwrap_or_default()
+}
+
+fn set_client_info(clients: &ConnectedClients, connection: u64, id: u32, label: Option<String>, channels: &[String]) {
+	clients.update(connection, |e| {
+		e.computer_id = Some(id);
+		e.label = label;
+	});
+	println!("{} subscribed to {}", describe(clients, connection), channels.join(", "));
+}
+
+async fn send_message<C: AsyncWrite + Unpin>(conn: &mut WriteHalf<C>, message: &ServerMessage) -> Result<(), std::io::Error> {
+	conn.send(Message::Text(serde_json::to_string(message).unwrap())).await?;
+	conn.flush().await
+}
+
+// only sent when it changed, reloads and channel changes usually don't touch it
+async fn update_bidirectional<C: AsyncWrite + Unpin>(conn: &mut WriteHalf<C>, current: &mut Vec<String>, new: Vec<String>) -> Result<(), std::io::Error> {
+	if *current == new {
+		return Ok(());
+	}
+	*current = new;
+	send_message(conn, &ServerMessage::Bidirectional { files: current.clone() }).await
+}
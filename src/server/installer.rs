@@ -0,0 +1,83 @@
+use std::io::Write;
+
+use flate2::{Compression, write::DeflateEncoder};
+use serde::Deserialize;
+
+use crate::{files::{B85_MINIFIED, LIBDEFLATE_MINIFIED, LZ4_MINIFIED}, structs::DataSync};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallerCompression {
+	// the decompressor is much smaller, which matters more than the ratio for small programs
+	#[default]
+	Lz4,
+	Deflate
+}
+
+// unpacks the archive to the same places sync.lua would put each file, paths are absolute on the computer either way
+const INSTALLER_BODY: &str = r#"local err, compressed = base85.decode(PAYLOAD)
+if err then error("installer is corrupted: " .. err) end
+local archive = decompress(compressed)
+local offset = 1
+local function u32()
+  local n = string.unpack(">I4", archive, offset)
+  offset = offset + 4
+  return n
+end
+local count = u32()
+for _ = 1, count do
+  local path_len, data_len = u32(), u32()
+  local path = string.sub(archive, offset, offset + path_len - 1)
+  local data = string.sub(archive, offset + path_len, offset + path_len + data_len - 1)
+  offset = offset + path_len + data_len
+  local dir = fs.getDir(path)
+  if dir ~= "" and not fs.exists(dir) then fs.makeDir(dir) end
+  local f = fs.open(path, "wb")
+  f.write(data)
+  f.close()
+  print("installed " .. path)
+end
+print("installed " .. count .. " file(s)")
+"#;
+
+// one lua script that contains every file along with the decompressor needed to unpack them,
+// so it can be ran with wget run on computers that have never seen the sync server
+pub fn build_installer(files: &[DataSync], channels: &[String], compression: InstallerCompression) -> String {
+	let mut entries: Vec<(&str, &[u8])> = files.iter().map(|e| (e.file_path.as_str(), &e.file_data[..])).collect();
+	// deflate_trickery output loads these at runtime
+	if files.iter().any(|e| e.file_data.windows(9).any(|w| w == b"/cc-sync/")) {
+		entries.push(("cc-sync/libdeflate.lua", LIBDEFLATE_MINIFIED.as_bytes()));
+		entries.push(("cc-sync/base85.lua", B85_MINIFIED.as_bytes()));
+	}
+	let mut archive = Vec::new();
+	archive.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+	for (path, data) in entries {
+		archive.extend_from_slice(&(path.len() as u32).to_be_bytes());
+		archive.extend_from_slice(&(data.len() as u32).to_be_bytes());
+		archive.extend_from_slice(path.as_bytes());
+		archive.extend_from_slice(data);
+	}
+	let (compressed, decompressor) = match compression {
+		InstallerCompression::Lz4 => (
+			lz4_flex::compress(&archive),
+			format!("local decompress = (function() {} end)().decompress", LZ4_MINIFIED)
+		),
+		InstallerCompression::Deflate => {
+			let mut deflate = DeflateEncoder::new(Vec::new(), Compression::best());
+			deflate.write_all(&archive).unwrap();
+			(
+				deflate.finish().unwrap(),
+				format!("local libDeflate = (function() {} end)().libDeflate\nlocal function decompress(data) return (libDeflate:DecompressDeflate(data)) end", LIBDEFLATE_MINIFIED)
+			)
+		}
+	};
+	// base85 has no quotes or backslashes, so it can go in a string as is
+	format!(
+		"-- installer for {}, generated by computercraft-sync\nlocal base85 = (function() {} end)()\n{}\nlocal PAYLOAD = \"{}\"\n{}",
+		channels.join(", "),
+		B85_MINIFIED,
+		decompressor,
+		base85::encode(&compressed),
+		INSTALLER_BODY
+	)
+}
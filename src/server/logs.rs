@@ -0,0 +1,61 @@
+use std::{collections::VecDeque, time::{SystemTime, UNIX_EPOCH}};
+
+use parking_lot::Mutex;
+
+// how many lines are kept for /logs, older ones are dropped
+const MAX_LOG_LINES: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLine {
+	pub time: u64,
+	pub computer_id: Option<u32>,
+	// how the computer is described in the console, with its label when it has one
+	pub computer: String,
+	pub channels: Vec<String>,
+	pub error: bool,
+	pub line: String
+}
+
+impl LogLine {
+	pub fn new(computer_id: Option<u32>, computer: String, channels: Vec<String>, error: bool, line: String) -> Self {
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		Self { time, computer_id, computer, channels, error, line }
+	}
+
+	pub fn format(&self) -> String {
+		format!("[{} | {}]{} {}", self.computer, self.channels.join(", "), if self.error { " error:" } else { "" }, self.line)
+	}
+}
+
+// output forwarded by computers, printed as it comes in and kept so it can be read over http
+pub struct LogBuffer {
+	lines: Mutex<VecDeque<LogLine>>
+}
+
+impl LogBuffer {
+	pub fn new() -> Self {
+		Self { lines: Mutex::new(VecDeque::new()) }
+	}
+
+	pub fn push(&self, line: LogLine) {
+		println!("{}", line.format());
+		let mut lines = self.lines.lock();
+		if lines.len() >= MAX_LOG_LINES {
+			lines.pop_front();
+		}
+		lines.push_back(line);
+	}
+
+	// the last `limit` lines, optionally only from one computer or channel
+	pub fn get(&self, computer_id: Option<u32>, channel: Option<&str>, limit: usize) -> Vec<LogLine> {
+		let lines = self.lines.lock();
+		let mut matching: Vec<LogLine> = lines.iter().rev()
+			.filter(|e| computer_id.is_none() || e.computer_id == computer_id)
+			.filter(|e| channel.is_none_or(|channel| e.channels.iter().any(|e| e == channel)))
+			.take(limit)
+			.cloned()
+			.collect();
+		matching.reverse();
+		matching
+	}
+}
@@ -2,4 +2,10 @@ mod server;
 pub mod util;
 pub mod handlers;
 pub mod file_watcher;
-pub use server::SyncServer;
\ No newline at end of file
+pub mod config_watcher;
+pub mod cache;
+pub mod clients;
+pub mod installer;
+pub mod logs;
+pub mod auth;
+pub use server::SyncServer;
@@ -1,133 +1,223 @@
-use std::{env::current_dir, path::PathBuf, sync::Arc};
+use std::{env::current_dir, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use futures_util::future::join_all;
 #[allow(unused)]
 use notify::{EventHandler, Watcher};
 use ohkami::{Config, Ohkami, Route, fang::Context};
 use parking_lot::RwLock;
-use tokio::sync::broadcast::{Receiver, Sender, channel};
-use crate::{server::{handlers::{downloads::{handle_download, handle_download_b85, handle_download_base_b85, handle_download_base_libdeflate, handle_download_base_lz4, handle_download_base_sync, handle_download_libdeflate, handle_download_lz4, handle_download_nomin, handle_download_sync}, root::handle_get_root, websocket::handle_subscribe}, util::get_files_for_channel}, structs::Project};
-use super::file_watcher::FileChanged;
+use tokio::{sync::{broadcast::{Receiver, Sender, channel}, watch}, time::timeout};
+use crate::{config::{ProjectOverrides, get_bind_addresses}, server::handlers::{commands::handle_command, downloads::{handle_download, handle_download_b85, handle_installer, handle_download_base_b85, handle_download_base_libdeflate, handle_download_base_lz4, handle_download_base_sync, handle_download_libdeflate, handle_download_lz4, handle_download_nomin, handle_download_sync}, root::{handle_get_logs, handle_get_root, handle_get_stats}, websocket::{SubscriptionContext, handle_subscribe}}, structs::Project};
+use super::{auth::ProjectToken, cache::ProcessedCache, clients::ConnectedClients, logs::LogBuffer, file_watcher::FileChanged, util::wait_for_shutdown};
 #[cfg(not(test))]
-use super::file_watcher::FileWatcher;
+use super::{config_watcher::ConfigWatcher, file_watcher::{FileWatcher, create_watcher}, util::get_all_files};
 
 type FileChangedType = (Arc<Sender<FileChanged>>, Arc<Receiver<FileChanged>>);
 
+// how long subscriptions get to send their close frames once shutting down
+const SHUTDOWN_TIMEOUT: u64 = 5;
+
 pub struct SyncServer {
 	pub project: Arc<RwLock<Project>>,
-	file_changed: FileChangedType
+	#[allow(dead_code)]
+	config_path: Option<PathBuf>,
+	file_changed: FileChangedType,
+	cache: Arc<ProcessedCache>,
+	clients: Arc<ConnectedClients>,
+	logs: Arc<LogBuffer>,
+	// every subscription and the watchers hold a receiver, so the sender knows when all of them have stopped
+	shutdown: Arc<watch::Sender<bool>>,
+	overrides: ProjectOverrides
 }
 
 impl SyncServer {
-	pub fn new(project: Project) -> Self {
+	pub fn new(project: Project, config_path: Option<PathBuf>) -> Self {
 		let fc = channel(1000);
 		let serv = SyncServer {
 			project: Arc::new(RwLock::new(project)),
-			file_changed: (Arc::new(fc.0), Arc::new(fc.1))
+			config_path,
+			file_changed: (Arc::new(fc.0), Arc::new(fc.1)),
+			cache: Arc::new(ProcessedCache::new()),
+			clients: Arc::new(ConnectedClients::new()),
+			logs: Arc::new(LogBuffer::new()),
+			shutdown: Arc::new(watch::Sender::new(false)),
+			overrides: ProjectOverrides::default()
 		};
 		serv
 	}
 
-	fn get_all_current_files(&self) -> Vec<PathBuf> {
-		let mut v = Vec::new();
-		let r = self.project.read();
-		let project_root = current_dir().unwrap().join(&r.root_dir);
-		for channel in &r.items {
-			let mut files = get_files_for_channel(&project_root, &channel);
-			v.append(&mut files);
-		}
-		v
+	// command line overrides that project.ron reloads have to keep applying
+	pub fn set_overrides(&mut self, overrides: ProjectOverrides) {
+		self.overrides = overrides;
 	}
 
-	pub fn start_server(&self) -> tokio::task::JoinHandle<()> {
+	// closes every subscription, stops the watchers and ends the task returned by start_server
+	#[allow(dead_code)]
+	pub fn shutdown(&self) {
+		self.shutdown.send_replace(true);
+	}
+
+	// finishes with an error when the server couldn't start at all
+	pub fn start_server(&self) -> tokio::task::JoinHandle<Result<(), String>> {
 		let _p = self.project.clone();
 		let project_root = current_dir().unwrap().join(_p.read().root_dir.clone());
 		let s1 = self.file_changed.0.clone();
 		let sender = self.file_changed.0.clone();
 		let port = _p.read().port.clone();
-		let files = self.get_all_current_files();
+		let cache = self.cache.clone();
+		let clients = self.clients.clone();
+		let logs = self.logs.clone();
 		#[cfg(not(test))]
-		tokio::spawn(async move {
-    	use std::collections::HashSet;
-			let mut all_existing_files: HashSet<PathBuf> = HashSet::new();
-			for file in files {
-				all_existing_files.insert(file);
+		{
+			use std::collections::HashSet;
+			use parking_lot::Mutex;
+			let files = get_all_files(&_p.read());
+			let config_path = self.config_path.clone();
+			let overrides = self.overrides.clone();
+			let project = _p.clone();
+			let cache = cache.clone();
+			let mut shutdown_rx = self.shutdown.subscribe();
+			// ohkami sets its own ctrl+C handler once it starts listening, which only stops it from accepting new connections.
+			// setting one first makes ohkami's fail, so open subscriptions get closed instead of holding the process until they time out
+			let signal_shutdown = self.shutdown.clone();
+			let signal_result = ctrlc::set_handler(move || {
+				if signal_shutdown.send_replace(true) {
+					println!("shutting down immediately");
+					std::process::exit(1);
+				}
+				println!("shutting down, ctrl+C again to force");
+			});
+			if let Err(e) = signal_result {
+				println!("failed to set ctrl+C handler: {}", e);
 			}
-			let handler = FileWatcher::new(sender, all_existing_files);
-			let mut watcher = notify::recommended_watcher(handler).unwrap();
-			watcher.watch(&project_root, notify::RecursiveMode::Recursive).unwrap();
-			std::future::pending::<()>().await;
-		});
+			tokio::spawn(async move {
+				let all_existing_files: Arc<RwLock<HashSet<PathBuf>>> = Arc::new(RwLock::new(files.into_iter().collect()));
+				let handler = FileWatcher::new(sender.clone(), all_existing_files.clone(), cache, project.clone());
+				let (watch_mode, poll_interval) = {
+					let project = project.read();
+					(project.watch_mode, Duration::from_millis(project.poll_interval_ms))
+				};
+				let watcher = match create_watcher(handler, &project_root, notify::RecursiveMode::Recursive, watch_mode, poll_interval) {
+					Ok(watcher) => Arc::new(Mutex::new(watcher)),
+					Err(e) => {
+						println!("failed to watch {}: {}, changes won't be synced", project_root.to_string_lossy(), e);
+						return;
+					}
+				};
+				// project.ron is watched through its parent directory since editors tend to replace the file on save
+				let _config_watcher = config_path.and_then(|config_path| {
+					let dir = config_path.parent().map(|e| e.to_path_buf()).unwrap_or_else(|| current_dir().unwrap());
+					let config_handler = ConfigWatcher::new(config_path, overrides, project, sender, all_existing_files, watcher.clone());
+					create_watcher(config_handler, &dir, notify::RecursiveMode::NonRecursive, watch_mode, poll_interval).inspect_err(|e| {
+						println!("failed to watch {}: {}, it won't be reloaded", dir.to_string_lossy(), e);
+					}).ok()
+				});
+				wait_for_shutdown(&mut shutdown_rx).await;
+				drop(_config_watcher);
+				drop(watcher);
+			});
+		}
+		let shutdown = self.shutdown.clone();
+		let bind_addresses = get_bind_addresses(&_p.read().bind_address).unwrap();
 		tokio::spawn(async move {
-			let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+			let mut shutdown_rx = shutdown.subscribe();
 			let mut cfg = Config::default();
 			cfg.keepalive_timeout = 5;
 			cfg.websocket_timeout = 14400;
-			Ohkami::new((
-				Context::new(_p),
-				Context::new(s1),
-				"/".GET(handle_get_root),
-				"/libdeflate.lua".GET(handle_download_libdeflate),
-				"/sync.lua".GET(handle_download_sync),
-				"/base85.lua".GET(handle_download_b85),
-				"/lz4.lua".GET(handle_download_lz4),
-				"/base-sync.lua".GET(handle_download_base_sync),
-				"/base-libdeflate.lua".GET(handle_download_base_libdeflate),
-				"/base-base85.lua".GET(handle_download_base_b85),
-				"/base-lz4.lua".GET(handle_download_base_lz4),
-				"/download".GET(handle_download),
-				"/download-nomin".GET(handle_download_nomin),
-				"/subscribe".GET(handle_subscribe)
-			)).howl_with(
-				cfg,
-				listener
-			).await;
+			let mut servers = Vec::new();
+			for address in bind_addresses {
+				let listener = match tokio::net::TcpListener::bind((address, port)).await {
+					Ok(listener) => listener,
+					Err(e) => {
+						println!("failed to listen on {}: {}", SocketAddr::new(address, port), e);
+						continue;
+					}
+				};
+				// ohkami can only serve one listener, so every address gets its own router over the same state
+				servers.push(Ohkami::new((
+					Context::new(_p.clone()),
+					Context::new(cache.clone()),
+					Context::new(clients.clone()),
+					Context::new(logs.clone()),
+					Context::new(SubscriptionContext { file_changed: s1.clone(), cache: cache.clone(), shutdown: shutdown.clone(), clients: clients.clone(), logs: logs.clone() }),
+					ProjectToken(_p.clone()),
+					"/".GET(handle_get_root),
+					"/subscribe".GET(handle_subscribe),
+					"/stats".GET(handle_get_stats),
+					"/logs".GET(handle_get_logs),
+					"/commands".POST(handle_command),
+					"/installer".GET(handle_installer),
+					Ohkami::new((
+						"/libdeflate.lua".GET(handle_download_libdeflate),
+						"/sync.lua".GET(handle_download_sync),
+						"/base85.lua".GET(handle_download_b85),
+						"/lz4.lua".GET(handle_download_lz4),
+						"/base-sync.lua".GET(handle_download_base_sync),
+						"/base-libdeflate.lua".GET(handle_download_base_libdeflate),
+						"/base-base85.lua".GET(handle_download_base_b85),
+						"/base-lz4.lua".GET(handle_download_base_lz4),
+						"/download".GET(handle_download),
+						"/download-nomin".GET(handle_download_nomin)
+					))
+				)).howl_with(
+					cfg,
+					listener
+				));
+			}
+			if servers.is_empty() {
+				shutdown.send_replace(true);
+				return Err("could not listen on any address".to_string());
+			}
+			tokio::select! {
+				_ = join_all(servers) => {}
+				_ = wait_for_shutdown(&mut shutdown_rx) => {}
+			}
+			drop(shutdown_rx);
+			// the listener is gone at this point, subscriptions drop their receivers after sending a close frame
+			if timeout(Duration::from_secs(SHUTDOWN_TIMEOUT), shutdown.closed()).await.is_err() {
+				println!("{} subscription(s) didn't close in time", shutdown.receiver_count());
+			}
+			Ok(())
 		})
 	}
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{io::Read, time::Duration};
+  use std::{collections::{HashMap, HashSet}, io::Read, net::IpAddr, path::Path, sync::Arc, time::Duration};
 	use flate2::read::DeflateDecoder;
-	use crate::{server::{server::SyncServer, util::{chunk_batch, merge}}, structs::{DataSync, Project, ProjectItem, ProjectItemType, RequestType}, util::randstring};
+	use parking_lot::RwLock;
+	use crate::{cli::PROJECT_TEMPLATE, config::{ProjectOverrides, get_bind_addresses, load_project, validate_project}, rules::gotos::encode_gotos, server::{cache::{ProcessOptions, ProcessedCache}, clients::ClientState, file_watcher::{FileChanged, FileWatcher, create_watcher}, installer::{InstallerCompression, build_installer}, server::SyncServer, util::{RenameChanges, UploadResult, apply_manifest, apply_upload, check_protocol_version, chunk_batch, get_changed_channels, get_channel_output, get_checksum, get_content_hash, get_export_path, get_rename_changes, merge, process_file}}, structs::{CommandResult, DataSync, Directory, ManifestEntry, PROTOCOL_VERSION, Project, ProjectItem, ProjectItemType, RequestType, SERVER_CAPABILITIES, ServerMessage, WatchMode}, util::randstring};
 
 	#[tokio::test]
 	async fn get_channels() {
-		let project = Project {
-			deflate_trickery: None,
-			lz_on_deflate: None,
-			items: vec![
-				ProjectItem {
-					channel_name: "hi".to_string(),
-					deflate_trickery: None,
-					directories: None,
-					item_type: ProjectItemType::Library,
-					files: None,
-					required_channels: None,
-					minify: None,
-					require_prefix: None,
-					prefix_exclusions: None
-				},
-				ProjectItem {
-					channel_name: "hello".to_string(),
-					deflate_trickery: None,
-					directories: None,
-					item_type: ProjectItemType::Resource,
-					files: None,
-					required_channels: None,
-					minify: None,
-					require_prefix: None,
-					prefix_exclusions: None
-				}
-			],
-			max_uncompressed_request_size: 30000,
-			minify: None,
-			root_dir: "testdir".to_string(),
-			require_prefix: None,
-			prefix_exclusions: None,
-			port: 8001,
-			sync_interval: 1
-		};
-		let serv = SyncServer::new(project);
+		let project = test_project(8001, "testdir", vec![
+			ProjectItem {
+				channel_name: "hi".to_string(),
+				deflate_trickery: None,
+				directories: None,
+				item_type: ProjectItemType::Library,
+				files: None,
+				required_channels: None,
+				bidirectional: None,
+				auth_token: None,
+				minify: None,
+				require_prefix: None,
+				prefix_exclusions: None
+			},
+			ProjectItem {
+				channel_name: "hello".to_string(),
+				deflate_trickery: None,
+				directories: None,
+				item_type: ProjectItemType::Resource,
+				files: None,
+				required_channels: None,
+				bidirectional: None,
+				auth_token: None,
+				minify: None,
+				require_prefix: None,
+				prefix_exclusions: None
+			}
+		]);
+		let serv = SyncServer::new(project, None);
 		let handle = serv.start_server();
 
 		tokio::time::sleep(Duration::from_millis(1)).await;
@@ -144,15 +234,559 @@ mod tests {
 			trimmed.to_string()
 		};
 		assert_eq!(full_str, "hi - library\nhello - resource".to_string());
-		handle.abort();
+		serv.shutdown();
+		handle.await.unwrap().unwrap();
+	}
+
+	// a project with every optional setting left out, tests change whatever they need afterwards
+	fn test_project(port: u16, root_dir: &str, items: Vec<ProjectItem>) -> Project {
+		Project {
+			deflate_trickery: None,
+			lz_on_deflate: None,
+			items,
+			max_uncompressed_request_size: 30000,
+			minify: None,
+			root_dir: root_dir.to_string(),
+			require_prefix: None,
+			prefix_exclusions: None,
+			port,
+			bind_address: None,
+			admin_token: None,
+			allow_local_commands: false,
+			auth_token: None,
+			sync_interval: 1,
+			debounce_ms: 100,
+			watch_mode: WatchMode::Auto,
+			poll_interval_ms: 1000
+		}
+	}
+
+	// a library channel syncing the directory with the same name
+	fn test_channel(name: &str) -> ProjectItem {
+		ProjectItem {
+			channel_name: name.to_string(),
+			deflate_trickery: None,
+			directories: Some(vec![Directory { path: name.to_string(), minify: None, deflate_trickery: None, require_prefix: None, prefix_exclusions: None }]),
+			item_type: ProjectItemType::Library,
+			files: None,
+			required_channels: None,
+			bidirectional: None,
+			auth_token: None,
+			minify: None,
+			require_prefix: None,
+			prefix_exclusions: None
+		}
+	}
+
+	// a file the way process_tup would send it
+	fn test_sync(path: &str, data: Vec<u8>) -> DataSync {
+		DataSync { file_path: path.to_string(), file_hash: get_content_hash(&data), total_size: data.len(), checksum: get_checksum(&data), file_data: data, transfer_id: 0 }
+	}
+
+	// just enough of a websocket client to talk to /subscribe, frames from the client are masked with a zero key
+	async fn ws_connect(port: u16, path: &str) -> tokio::net::TcpStream {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+		let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+		let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", path, port);
+		stream.write_all(request.as_bytes()).await.unwrap();
+		let mut response = Vec::new();
+		while !response.ends_with(b"\r\n\r\n") {
+			response.push(stream.read_u8().await.unwrap());
+		}
+		assert!(response.starts_with(b"HTTP/1.1 101"));
+		stream
+	}
+
+	// connects the way the current sync.lua does
+	async fn ws_handshake(port: u16, path: &str) -> tokio::net::TcpStream {
+		let mut stream = ws_connect(port, path).await;
+		ws_send_text(&mut stream, &format!(r#"{{"type":"hello","version":{}}}"#, PROTOCOL_VERSION)).await;
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x1);
+		let hello: ServerMessage = serde_json::from_slice(&payload).unwrap();
+		assert_eq!(hello, ServerMessage::Hello { version: PROTOCOL_VERSION, capabilities: SERVER_CAPABILITIES.iter().map(|e| e.to_string()).collect() });
+		stream
+	}
+
+	async fn ws_send_text(stream: &mut tokio::net::TcpStream, text: &str) {
+		ws_send(stream, 0x1, text.as_bytes()).await;
+	}
+
+	async fn ws_send(stream: &mut tokio::net::TcpStream, opcode: u8, payload: &[u8]) {
+		use tokio::io::AsyncWriteExt;
+		let mut frame = vec![0x80 | opcode];
+		if payload.len() < 126 {
+			frame.push(0x80 | payload.len() as u8);
+		}
+		else {
+			frame.push(0x80 | 126);
+			frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+		}
+		frame.extend_from_slice(&[0; 4]);
+		frame.extend_from_slice(payload);
+		stream.write_all(&frame).await.unwrap();
+	}
+
+	async fn ws_read_frame(stream: &mut tokio::net::TcpStream) -> (u8, Vec<u8>) {
+		use tokio::io::AsyncReadExt;
+		let opcode = stream.read_u8().await.unwrap() & 0x0f;
+		let len = match stream.read_u8().await.unwrap() & 0x7f {
+			126 => stream.read_u16().await.unwrap() as usize,
+			127 => stream.read_u64().await.unwrap() as usize,
+			len => len as usize
+		};
+		let mut payload = vec![0; len];
+		stream.read_exact(&mut payload).await.unwrap();
+		(opcode, payload)
+	}
+
+	// the next binary frame, inflated
+	async fn ws_read_sync(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(3), ws_read_frame(stream)).await.unwrap();
+		assert_eq!(opcode, 0x2);
+		let mut decoded = Vec::new();
+		DeflateDecoder::new(&payload[..]).read_to_end(&mut decoded).unwrap();
+		decoded
+	}
+
+	#[tokio::test]
+	async fn shutdown_closes_subscriptions() {
+		let project = test_project(8002, "testdir", vec![
+			ProjectItem {
+				channel_name: "hi".to_string(),
+				deflate_trickery: None,
+				directories: None,
+				item_type: ProjectItemType::Library,
+				files: None,
+				required_channels: None,
+				bidirectional: None,
+				auth_token: None,
+				minify: None,
+				require_prefix: None,
+				prefix_exclusions: None
+			}
+		]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		let mut stream = ws_handshake(8002, "/subscribe?channels=hi").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+
+		serv.shutdown();
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x8);
+		assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1001);
+		assert_eq!(&payload[2..], b"server shutting down");
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn sync_lua_handshake() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(root.join("lib")).unwrap();
+		std::fs::write(root.join("lib/a.lua"), "return 1").unwrap();
+		std::fs::write(root.join("lib/b.lua"), "return 2").unwrap();
+		let project = test_project(8010, &root.to_string_lossy(), vec![test_channel("lib")]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		// sync.lua introduces the computer between the handshake and its manifest
+		let mut stream = ws_handshake(8010, "/subscribe?channels=lib").await;
+		ws_send_text(&mut stream, r#"{"type":"client_info","id":7,"label":"turtle"}"#).await;
+		ws_send_text(&mut stream, &format!(r#"{{"type":"manifest","files":{{"lib/a.lua":{{"hash":"{}","size":8}}}}}}"#, get_content_hash(b"return 1"))).await;
+		let initial = ws_read_sync(&mut stream).await;
+		assert!(initial.ends_with(b"lib/b.luareturn 2"));
+		assert!(!initial.windows(9).any(|e| e == b"lib/a.lua"));
+		let clients = serv.clients.list();
+		assert_eq!((clients[0].1.computer_id, clients[0].1.label.as_deref()), (Some(7), Some("turtle")));
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn client_messages() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(root.join("lib")).unwrap();
+		std::fs::write(root.join("lib/a.lua"), "return 1").unwrap();
+		let project = test_project(8004, &root.to_string_lossy(), vec![test_channel("lib")]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		let mut stream = ws_handshake(8004, "/subscribe?channels=lib").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		let initial = ws_read_sync(&mut stream).await;
+		assert_eq!(initial[0], 0);
+		assert!(initial.ends_with(b"lib/a.luareturn 1"));
+
+		// a file that failed verification on the computer is sent again on the next sync
+		ws_send_text(&mut stream, r#"{"type":"resend","path":"lib/a.lua"}"#).await;
+		assert_eq!(ws_read_sync(&mut stream).await, initial);
+		// directories the computer couldn't move come with all their files
+		ws_send_text(&mut stream, r#"{"type":"resend","path":"lib"}"#).await;
+		assert_eq!(ws_read_sync(&mut stream).await, initial);
+
+		ws_send_text(&mut stream, r#"{"type":"client_info","id":5,"label":"turtle"}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"ack","files":["lib/a.lua"]}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"log","line":"hello"}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"log","line":"attempt to call nil","error":true}"#).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		let logs = reqwest::get("http://127.0.0.1:8004/logs").await.unwrap().text().await.unwrap();
+		let lines: Vec<&str> = logs.lines().map(|e| e.split_once(' ').unwrap().1).collect();
+		assert_eq!(lines, vec!["[computer 5 (turtle) | lib] hello", "[computer 5 (turtle) | lib] error: attempt to call nil"]);
+		assert_eq!(reqwest::get("http://127.0.0.1:8004/logs?computer=5&lines=1").await.unwrap().text().await.unwrap().lines().count(), 1);
+		assert_eq!(reqwest::get("http://127.0.0.1:8004/logs?computer=6").await.unwrap().text().await.unwrap(), "");
+		assert_eq!(reqwest::get("http://127.0.0.1:8004/logs?channel=other").await.unwrap().text().await.unwrap(), "");
+		let clients = serv.clients.list();
+		assert_eq!(clients.len(), 1);
+		assert_eq!(clients[0].1, ClientState { computer_id: Some(5), label: Some("turtle".to_string()), channels: vec!["lib".to_string()], acknowledged: 1 });
+		assert_eq!(clients[0].1.describe(clients[0].0), "computer 5 (turtle)");
+
+		// clients are forgotten once they disconnect
+		drop(stream);
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(serv.clients.list().is_empty());
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn channel_changes() {
+		let root = std::env::temp_dir().join(randstring(12));
+		for (dir, content) in [("lib", "return 1"), ("extra", "return 2")] {
+			std::fs::create_dir_all(root.join(dir)).unwrap();
+			std::fs::write(root.join(dir).join("a.lua"), content).unwrap();
+		}
+		let project = test_project(8006, &root.to_string_lossy(), vec![test_channel("lib"), test_channel("extra")]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		let mut stream = ws_handshake(8006, "/subscribe?channels=lib").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		assert!(ws_read_sync(&mut stream).await.ends_with(b"lib/a.luareturn 1"));
+
+		// the server answers with what the computer ended up subscribed to, before the files of the new channels
+		let confirmed = async |stream: &mut tokio::net::TcpStream| {
+			let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(stream)).await.unwrap();
+			assert_eq!(opcode, 0x1);
+			serde_json::from_slice::<ServerMessage>(&payload).unwrap()
+		};
+		ws_send_text(&mut stream, r#"{"type":"subscribe","channels":["extra","missing"]}"#).await;
+		assert_eq!(confirmed(&mut stream).await, ServerMessage::Channels { channels: vec!["lib".to_string(), "extra".to_string()] });
+		assert!(ws_read_sync(&mut stream).await.ends_with(b"extra/a.luareturn 2"));
+		assert_eq!(serv.clients.list()[0].1.channels, vec!["lib".to_string(), "extra".to_string()]);
+
+		// only files that no remaining channel has are deleted
+		ws_send_text(&mut stream, r#"{"type":"unsubscribe","channels":["extra"]}"#).await;
+		assert_eq!(confirmed(&mut stream).await, ServerMessage::Channels { channels: vec!["lib".to_string()] });
+		let mut deletion = vec![1, 0, 0, 0, 1, 0, 0, 0, 11];
+		deletion.extend_from_slice(b"extra/a.lua");
+		assert_eq!(ws_read_sync(&mut stream).await, deletion);
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn bidirectional() {
+		let root = std::env::temp_dir().join(randstring(12));
+		for dir in ["lib", "min"] {
+			std::fs::create_dir_all(root.join(dir)).unwrap();
+			std::fs::write(root.join(dir).join("a.lua"), "return 1").unwrap();
+		}
+		let channel = |name: &str, minify: Option<bool>| {
+			let mut item = ProjectItem { bidirectional: Some(true), ..test_channel(name) };
+			item.directories.as_mut().unwrap()[0].minify = minify;
+			item
+		};
+		let project = test_project(8007, &root.to_string_lossy(), vec![channel("lib", None), channel("min", Some(true))]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		// minified files can't be written back, so only lib is offered
+		let mut stream = ws_handshake(8007, "/subscribe?channels=lib,min").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		let (opcode, _) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x2);
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x1);
+		assert_eq!(serde_json::from_slice::<ServerMessage>(&payload).unwrap(), ServerMessage::Bidirectional { files: vec!["lib/a.lua".to_string()] });
+
+		let upload = |path: &str, base_hash: &str, content: &str| {
+			let mut v = vec![0];
+			v.extend_from_slice(&(path.len() as u32).to_be_bytes());
+			v.extend_from_slice(base_hash.as_bytes());
+			v.extend_from_slice(path.as_bytes());
+			v.extend_from_slice(content.as_bytes());
+			v
+		};
+		let base_hash = get_content_hash(b"return 1");
+		ws_send(&mut stream, 0x2, &upload("min/a.lua", &base_hash, "return 2")).await;
+		ws_send(&mut stream, 0x2, &upload("lib/a.lua", &base_hash, "return 2")).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(std::fs::read_to_string(root.join("lib/a.lua")).unwrap(), "return 2");
+		assert_eq!(std::fs::read_to_string(root.join("min/a.lua")).unwrap(), "return 1");
+
+		// files created after connecting can be sent back too, deleted ones stop being offered
+		let next_bidirectional = async |stream: &mut tokio::net::TcpStream| loop {
+			let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(stream)).await.unwrap();
+			if opcode == 0x1 && let ServerMessage::Bidirectional { files } = serde_json::from_slice(&payload).unwrap() {
+				return files;
+			}
+		};
+		std::fs::write(root.join("lib/b.lua"), "return 3").unwrap();
+		serv.file_changed.0.send(FileChanged::Changed { path: root.join("lib/b.lua") }).unwrap();
+		assert_eq!(next_bidirectional(&mut stream).await, vec!["lib/a.lua".to_string(), "lib/b.lua".to_string()]);
+		std::fs::remove_file(root.join("lib/b.lua")).unwrap();
+		serv.file_changed.0.send(FileChanged::Deleted { path: root.join("lib/b.lua") }).unwrap();
+		assert_eq!(next_bidirectional(&mut stream).await, vec!["lib/a.lua".to_string()]);
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+
+		// an edit of a version that changed on disk since is kept as a copy
+		let conflicts = root.join("conflicts");
+		let file = root.join("lib/a.lua");
+		assert_eq!(apply_upload(&file, "lib/a.lua", b"return 2", &base_hash, &conflicts, "computer-1").unwrap(), UploadResult::Written);
+		let UploadResult::Conflict(copy) = apply_upload(&file, "lib/a.lua", b"return 3", &base_hash, &conflicts, "computer-1").unwrap() else {
+			panic!("expected a conflict");
+		};
+		assert!(copy.starts_with(conflicts.join("lib")) && copy.file_name().unwrap().to_string_lossy().starts_with("a.lua.computer-1-"));
+		assert_eq!(std::fs::read_to_string(&copy).unwrap(), "return 3");
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "return 2");
+		assert_eq!(apply_upload(&file, "lib/a.lua", b"return 4", &get_content_hash(b"return 2"), &conflicts, "computer-1").unwrap(), UploadResult::Written);
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "return 4");
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn remote_commands() {
+		let mut project = test_project(8008, "testdir", Vec::new());
+		project.admin_token = Some("admin".to_string());
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		let mut stream = ws_handshake(8008, "/subscribe?channels=lib").await;
+		ws_send_text(&mut stream, r#"{"type":"client_info","id":5}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+
+		let send = |body: &'static str| tokio::spawn(async move {
+			let client = reqwest::Client::new();
+			let response = client.post("http://127.0.0.1:8008/commands").header("Content-Type", "application/json").bearer_auth("admin").body(body).send().await.unwrap();
+			serde_json::from_str::<Vec<CommandResult>>(&response.text().await.unwrap()).unwrap()
+		});
+		let request = send(r#"{"channel":"lib","type":"run","program":"update","args":["all"]}"#);
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x1);
+		let ServerMessage::Run { id, program, args } = serde_json::from_slice(&payload).unwrap() else {
+			panic!("expected a run message");
+		};
+		assert_eq!((program.as_str(), args), ("update", vec!["all".to_string()]));
+		ws_send_text(&mut stream, &format!(r#"{{"type":"command_result","id":{},"success":true,"output":"updated"}}"#, id)).await;
+		let results = request.await.unwrap();
+		assert_eq!(results, vec![CommandResult { computer: "computer 5".to_string(), computer_id: Some(5), success: true, output: "updated".to_string() }]);
+
+		// computers that aren't subscribed or don't match aren't sent anything
+		assert!(send(r#"{"channel":"lib","computer":6,"type":"reboot"}"#).await.unwrap().is_empty());
+		assert!(send(r#"{"channel":"other","type":"reboot"}"#).await.unwrap().is_empty());
+
+		// a computer that disconnects doesn't leave the request waiting
+		let request = send(r#"{"channel":"lib","type":"reboot"}"#);
+		let (_, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert!(matches!(serde_json::from_slice(&payload).unwrap(), ServerMessage::Reboot { .. }));
+		drop(stream);
+		let results = tokio::time::timeout(Duration::from_secs(2), request).await.unwrap().unwrap();
+		assert_eq!(results[0].output, "disconnected before answering");
+
+		// coming from this machine isn't enough, tunnels do too
+		let status = |token: Option<&'static str>| async move {
+			let request = reqwest::Client::new().post("http://127.0.0.1:8008/commands").header("Content-Type", "application/json").body(r#"{"channel":"lib","type":"reboot"}"#);
+			let request = match token {
+				Some(token) => request.bearer_auth(token),
+				None => request
+			};
+			request.send().await.unwrap().status().as_u16()
+		};
+		assert_eq!(status(None).await, 403);
+		assert_eq!(status(Some("wrong")).await, 403);
+		serv.project.write().allow_local_commands = true;
+		assert_eq!(status(None).await, 403);
+		serv.project.write().admin_token = None;
+		assert_eq!(status(None).await, 200);
+		serv.project.write().allow_local_commands = false;
+		assert_eq!(status(None).await, 403);
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn protocol_handshake() {
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION)).is_ok());
+		assert!(check_protocol_version(None).unwrap_err().contains("re-run /download"));
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION - 1)).unwrap_err().contains("re-run /download"));
+		assert!(check_protocol_version(Some(PROTOCOL_VERSION + 1)).unwrap_err().contains("update the server"));
+		// sync.lua has to be updated along with the server
+		assert!(std::fs::read_to_string("lua/sync.lua").unwrap().contains(&format!("local protocolVersion = {}\n", PROTOCOL_VERSION)));
+
+		let project = test_project(8005, "testdir", Vec::new());
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		// sync.lua from before the handshake starts with its manifest
+		let mut stream = ws_connect(8005, "/subscribe?channels=").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+		assert_eq!(opcode, 0x8);
+		assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1008);
+		assert!(String::from_utf8_lossy(&payload[2..]).contains("re-run /download"));
+
+		let stream = ws_handshake(8005, "/subscribe?channels=").await;
+		drop(stream);
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn auth_tokens() {
+		let channel = |name: &str, required: Option<&str>, token: Option<&str>| ProjectItem {
+			item_type: ProjectItemType::Resource,
+			directories: None,
+			required_channels: required.map(|e| vec![e.to_string()]),
+			auth_token: token.map(|e| e.to_string()),
+			..test_channel(name)
+		};
+		let mut project = test_project(8009, &std::env::temp_dir().to_string_lossy(), vec![channel("open", None, None), channel("locked", None, Some("inner")), channel("wrapper", Some("locked"), None)]);
+		project.auth_token = Some("outer".to_string());
+		assert!(validate_project(&project).is_ok());
+		project.items[1].auth_token = Some("in\"ner".to_string());
+		assert_eq!(validate_project(&project).unwrap_err().len(), 1);
+		project.items[1].auth_token = Some("inner".to_string());
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		let status = |path: &'static str| async move { reqwest::get(format!("http://127.0.0.1:8009{}", path)).await.unwrap().status().as_u16() };
+		assert_eq!(status("/").await, 401);
+		assert_eq!(status("/?token=wrong").await, 401);
+		assert_eq!(status("/?token=outer").await, 200);
+		assert_eq!(status("/sync.lua").await, 401);
+		assert_eq!(status("/sync.lua?token=outer").await, 200);
+		let bearer = reqwest::Client::new().get("http://127.0.0.1:8009/stats").bearer_auth("outer").send().await.unwrap();
+		assert_eq!(bearer.status().as_u16(), 200);
+		// channels with their own token need it even when only pulled in through required_channels
+		assert_eq!(status("/installer?channels=wrapper&token=outer").await, 401);
+		assert_eq!(status("/installer?channels=wrapper&token=outer,inner").await, 200);
+		// /commands needs the project's token on top of admin_token
+		serv.project.write().admin_token = Some("admin".to_string());
+		let command = |path: &'static str| async move {
+			let request = reqwest::Client::new().post(format!("http://127.0.0.1:8009{}", path)).header("Content-Type", "application/json").bearer_auth("admin");
+			request.body(r#"{"channel":"open","type":"reboot"}"#).send().await.unwrap().status().as_u16()
+		};
+		assert_eq!(command("/commands").await, 401);
+		assert_eq!(command("/commands?token=outer").await, 200);
+
+		// only tokens the project knows end up in the script
+		let script = reqwest::get("http://127.0.0.1:8009/download?token=outer,bogus").await.unwrap().text().await.unwrap();
+		assert!(script.starts_with("settings.set(\"sync.token\", \"outer\") settings.save() "));
+		assert!(script.contains("/sync.lua?token=outer sync.lua"));
+		assert!(!script.contains("bogus"));
+		let script = reqwest::get("http://127.0.0.1:8009/download-nomin?token=outer").await.unwrap().text().await.unwrap();
+		assert!(script.contains("/base-lz4.lua?token=outer cc-sync/llz4.lua"));
+
+		for (path, reason) in [("/subscribe?channels=open", "missing or wrong token"), ("/subscribe?channels=wrapper&token=outer", "missing or wrong token for locked")] {
+			let mut stream = ws_connect(8009, path).await;
+			let (opcode, payload) = tokio::time::timeout(Duration::from_secs(2), ws_read_frame(&mut stream)).await.unwrap();
+			assert_eq!(opcode, 0x8);
+			assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1008);
+			assert_eq!(String::from_utf8_lossy(&payload[2..]), reason);
+		}
+
+		let mut stream = ws_handshake(8009, "/subscribe?channels=open&token=outer").await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"subscribe","channels":["locked","wrapper"]}"#).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(serv.clients.list()[0].1.channels, vec!["open".to_string()]);
+		drop(stream);
+
+		let mut stream = ws_handshake(8009, "/subscribe?channels=wrapper&token=outer,inner").await;
+		ws_send_text(&mut stream, r#"{"type":"client_info","id":3}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"manifest","files":{}}"#).await;
+		ws_send_text(&mut stream, r#"{"type":"log","line":"secret"}"#).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(serv.clients.list().iter().any(|(_, client)| client.channels == vec!["wrapper".to_string()]));
+		// what computers on locked channels print or are doing needs the channel's token too
+		let text = |path: &'static str| async move { reqwest::get(format!("http://127.0.0.1:8009{}", path)).await.unwrap().text().await.unwrap() };
+		assert_eq!(text("/logs?token=outer").await, "");
+		assert!(text("/logs?token=outer,inner").await.ends_with("[computer 3 | wrapper] secret\n"));
+		assert!(!text("/stats?token=outer").await.contains("computer 3"));
+		assert!(text("/stats?token=outer,inner").await.contains("computer 3 - wrapper"));
+		drop(stream);
+
+		serv.shutdown();
+		tokio::time::timeout(Duration::from_secs(2), handle).await.unwrap().unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn bind_addresses() {
+		assert_eq!(get_bind_addresses(&None).unwrap(), vec![IpAddr::from([127, 0, 0, 1])]);
+		let parsed = get_bind_addresses(&Some(vec!["0.0.0.0".to_string(), "[::1]".to_string(), "::".to_string()])).unwrap();
+		assert_eq!(parsed, vec![IpAddr::from([0, 0, 0, 0]), IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]), IpAddr::from([0u16; 8])]);
+		assert_eq!(get_bind_addresses(&Some(vec!["localhost".to_string(), "1.2.3".to_string()])).unwrap_err().len(), 2);
+		assert!(get_bind_addresses(&Some(Vec::new())).is_err());
+
+		let mut project = test_project(8003, "testdir", Vec::new());
+		project.bind_address = Some(vec!["127.0.0.1".to_string(), "::1".to_string()]);
+		let serv = SyncServer::new(project, None);
+		let handle = serv.start_server();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+
+		// the bootstrap script points at whichever address it was downloaded from
+		for host in ["127.0.0.1:8003", "[::1]:8003"] {
+			let script = reqwest::get(format!("http://{}/download", host)).await.unwrap().text().await.unwrap();
+			assert!(script.contains(&format!("wget http://{}/sync.lua", host)));
+		}
+
+		// a second server can't take the same port, which has to show up as an error instead of a clean exit
+		let mut taken = test_project(8003, "testdir", Vec::new());
+		taken.bind_address = Some(vec!["127.0.0.1".to_string()]);
+		let result = tokio::time::timeout(Duration::from_secs(2), SyncServer::new(taken, None).start_server()).await.unwrap().unwrap();
+		assert_eq!(result, Err("could not listen on any address".to_string()));
+
+		serv.shutdown();
+		handle.await.unwrap().unwrap();
 	}
 
 	#[test]
 	fn chunking() {
 		let requests = vec![
-			RequestType::Resource { data: DataSync { file_path: "hello/hi".to_string(), file_data: randstring(20) } },
-			RequestType::Library { data: DataSync { file_path: "hello/hi2".to_string(), file_data: randstring(20) } },
-			RequestType::Script { data: DataSync { file_path: "hello/hi3".to_string(), file_data: randstring(20) } },
+			RequestType::Resource { data: test_sync("hello/hi", randstring(20).into_bytes()) },
+			RequestType::Library { data: test_sync("hello/hi2", randstring(20).into_bytes()) },
+			RequestType::Script { data: test_sync("hello/hi3", randstring(20).into_bytes()) },
 		];
 
 		let chunked = chunk_batch(requests, 5);
@@ -160,6 +794,121 @@ mod tests {
 		assert_eq!(chunked.len(), 12);
 	}
 
+	#[test]
+	fn chunk_reassembly() {
+		let file = |path: &str, data: Vec<u8>| RequestType::Library { data: test_sync(path, data) };
+		let a: Vec<u8> = (0..=255).cycle().take(1000).collect();
+		let b: Vec<u8> = (0..=255).rev().cycle().take(700).collect();
+		let requests = vec![
+			file("a.lua", a.clone()),
+			RequestType::Deletion { files: vec!["old.lua".to_string()] },
+			file("b.lua", b.clone()),
+			file("small.lua", b"return 1".to_vec())
+		];
+
+		// pieces are reassembled by transfer id, so the order they arrive in (and what's between them) doesn't matter
+		let mut pieces: Vec<RequestType> = chunk_batch(requests, 300).into_iter().flatten().collect();
+		pieces.reverse();
+		let mut paths: HashMap<u32, String> = HashMap::new();
+		let mut parts: HashMap<u32, Vec<(u32, u32, Vec<u8>)>> = HashMap::new();
+		for piece in pieces {
+			match piece {
+				RequestType::Library { data } if data.transfer_id != 0 => {
+					paths.insert(data.transfer_id, data.file_path);
+					parts.entry(data.transfer_id).or_default().push((0, 0, data.file_data));
+				}
+				RequestType::Library { data } => assert_eq!(data.file_path, "small.lua"),
+				RequestType::Chunk { transfer_id, index, total, file_data } => parts.entry(transfer_id).or_default().push((index, total, file_data)),
+				RequestType::Deletion { .. } => {}
+				_ => panic!("unexpected request type")
+			}
+		}
+		assert_eq!(parts.len(), 2);
+		for (id, mut pieces) in parts {
+			pieces.sort_by_key(|e| e.0);
+			let total = pieces[1].1;
+			assert_eq!(pieces.len() as u32, total);
+			assert!(pieces.iter().enumerate().all(|(i, e)| e.0 == i as u32 && (i == 0 || e.1 == total)));
+			let reassembled: Vec<u8> = pieces.into_iter().flat_map(|e| e.2).collect();
+			match paths[&id].as_str() {
+				"a.lua" => assert_eq!(reassembled, a),
+				"b.lua" => assert_eq!(reassembled, b),
+				path => panic!("unexpected file {}", path)
+			}
+		}
+	}
+
+	#[test]
+	fn checksum() {
+		// has to match libDeflate:Adler32 on the computer
+		assert_eq!(get_checksum(b""), 1);
+		assert_eq!(get_checksum(b"Wikipedia"), 0x11E60398);
+		let data: Vec<u8> = (0..=255).cycle().take(100000).collect();
+		let (mut a, mut b) = (1u64, 0u64);
+		for byte in &data {
+			a = (a + *byte as u64) % 65521;
+			b = (b + a) % 65521;
+		}
+		assert_eq!(get_checksum(&data), ((b << 16) | a) as u32);
+	}
+
+	#[test]
+	fn binary_resources() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		let file = root.join("sound.dfpwm");
+		let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+		std::fs::write(&file, &data).unwrap();
+		let cache = ProcessedCache::new();
+		let options = ProcessOptions { item_type: ProjectItemType::Resource, minify: false, deflate: false, bundle: false, require_prefix: None, prefix_exclusions: None };
+
+		let processed = cache.get_or_process(&file, &root, &options).unwrap();
+		assert_eq!(processed, data);
+
+		let chunked = chunk_batch(vec![RequestType::Resource { data: test_sync("sound.dfpwm", processed) }], 300);
+		let mut reassembled = Vec::new();
+		for request in chunked.into_iter().flatten() {
+			match request {
+				RequestType::Resource { data: first } => {
+					// the first chunk carries what the whole file is checked against once it's written
+					assert_eq!(first.total_size, data.len());
+					assert_eq!(first.checksum, get_checksum(&data));
+					reassembled.extend(first.file_data)
+				}
+				RequestType::Chunk { file_data, .. } => reassembled.extend(file_data),
+				_ => panic!("unexpected request type")
+			}
+		}
+		assert_eq!(reassembled, data);
+
+		// deflate_trickery turns any resource into lua source
+		let deflated = cache.get_or_process(&file, &root, &ProcessOptions { deflate: true, ..options }).unwrap();
+		assert!(String::from_utf8(deflated).unwrap().starts_with("return require"));
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn manifest() {
+		let sync = |path: &str, content: &str| RequestType::Library { data: DataSync { file_path: path.to_string(), file_data: content.as_bytes().to_vec(), file_hash: get_content_hash(content.as_bytes()), total_size: 0, checksum: 0, transfer_id: 0 } };
+		let entry = |content: &str| ManifestEntry { hash: get_content_hash(content.as_bytes()), size: content.len() as u64 };
+		let batch = vec![sync("same.lua", "return 1"), sync("changed.lua", "return 2"), sync("new.lua", "return 3")];
+		let manifest = HashMap::from([
+			("same.lua".to_string(), entry("return 1")),
+			("changed.lua".to_string(), entry("return 1")),
+			("gone.lua".to_string(), entry("return 4")),
+			("other_channel.lua".to_string(), entry("return 5"))
+		]);
+		let owned = HashSet::from(["same.lua".to_string(), "changed.lua".to_string(), "new.lua".to_string(), "other_channel.lua".to_string()]);
+
+		let applied = apply_manifest(batch, &manifest, &owned);
+		assert_eq!(applied, vec![sync("changed.lua", "return 2"), sync("new.lua", "return 3"), RequestType::Deletion { files: vec!["gone.lua".to_string()] }]);
+
+		// a file cut short by a disconnect mid-transfer still has the right hash in the manifest, its size doesn't match though
+		let truncated = HashMap::from([("same.lua".to_string(), ManifestEntry { hash: get_content_hash(b"return 1"), size: 4 })]);
+		assert_eq!(apply_manifest(vec![sync("same.lua", "return 1")], &truncated, &owned).len(), 1);
+	}
+
 	#[test]
 	fn merging() {
 		let requests = vec![
@@ -176,4 +925,536 @@ mod tests {
 
 		assert!(merged.iter().eq(expected.iter()), "merged is not equal to expected vec");
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn project_overrides() {
+		let dir = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(dir.join("src/lib")).unwrap();
+		let config = dir.join("project.ron");
+		std::fs::write(&config, PROJECT_TEMPLATE).unwrap();
+
+		let project = load_project(&config, &ProjectOverrides { root_dir: Some(dir.join("src").to_string_lossy().to_string()), ..Default::default() }).unwrap();
+		assert_eq!(project.port, 10234);
+		assert_eq!(project.bind_address, None);
+		assert_eq!(project.items[0].channel_name, "lib");
+
+		let overrides = ProjectOverrides {
+			port: Some(9000),
+			root_dir: Some(dir.join("src/lib").to_string_lossy().to_string()),
+			bind_address: Some(vec!["::".to_string()])
+		};
+		let project = load_project(&config, &overrides).unwrap();
+		assert_eq!(project.port, 9000);
+		assert_eq!(project.root_dir, dir.join("src/lib").to_string_lossy());
+		assert_eq!(project.bind_address, Some(vec!["::".to_string()]));
+
+		// overrides are validated like the config itself
+		let missing_root = ProjectOverrides { root_dir: Some(dir.join("missing").to_string_lossy().to_string()), ..Default::default() };
+		assert!(load_project(&config, &missing_root).is_err());
+		let bad_bind = ProjectOverrides { root_dir: overrides.root_dir.clone(), bind_address: Some(vec!["nope".to_string()]), ..Default::default() };
+		assert!(load_project(&config, &bad_bind).is_err());
+	}
+
+	#[test]
+	fn reload_diff() {
+		let options = ron::Options::default()
+			.with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+		let old: Project = options.from_str(r#"Project(root_dir: "src", max_uncompressed_request_size: 1000, port: 8002, items: [
+			ProjectItem(type: Library, channel_name: "kept"),
+			ProjectItem(type: Library, channel_name: "edited"),
+			ProjectItem(type: Script, channel_name: "removed"),
+		])"#).unwrap();
+		let new: Project = options.from_str(r#"Project(root_dir: "src", max_uncompressed_request_size: 1000, port: 8002, items: [
+			ProjectItem(type: Library, channel_name: "kept"),
+			ProjectItem(type: Library, channel_name: "edited", minify: true),
+			ProjectItem(type: Resource, channel_name: "added"),
+		])"#).unwrap();
+
+		assert_eq!(get_changed_channels(&old, &new), vec!["edited".to_string(), "added".to_string(), "removed".to_string()]);
+
+		let mut minified = new.clone();
+		minified.minify = Some(true);
+
+		assert_eq!(get_changed_channels(&new, &minified), vec!["kept".to_string(), "edited".to_string(), "added".to_string()]);
+	}
+
+	#[test]
+	fn cache_hits() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		let file = root.join("data.txt");
+		std::fs::write(&file, randstring(20)).unwrap();
+		let cache = ProcessedCache::new();
+		let options = ProcessOptions { item_type: ProjectItemType::Resource, minify: false, deflate: false, bundle: false, require_prefix: None, prefix_exclusions: None };
+
+		let first = cache.get_or_process(&file, &root, &options);
+		let second = cache.get_or_process(&file, &root, &options);
+		assert_eq!(first, second);
+		assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+		let deflated = ProcessOptions { deflate: true, ..options.clone() };
+		cache.get_or_process(&file, &root, &deflated).unwrap();
+		assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+		let changed = randstring(20);
+		std::fs::write(&file, &changed).unwrap();
+		assert_eq!(cache.get_or_process(&file, &root, &options), Ok(changed.into_bytes()));
+		assert_eq!((cache.hits(), cache.misses()), (1, 3));
+
+		cache.invalidate(&file);
+		cache.get_or_process(&file, &root, &options).unwrap();
+		assert_eq!((cache.hits(), cache.misses()), (1, 4));
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn debounced_file_events() {
+		use notify::{Event, EventHandler, EventKind, event::{CreateKind, DataChange, ModifyKind, RemoveKind}};
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		let mut project = test_project(8000, &root.to_string_lossy(), Vec::new());
+		project.debounce_ms = 50;
+		let (sender, mut receiver) = tokio::sync::broadcast::channel(100);
+		let known = Arc::new(RwLock::new(HashSet::new()));
+		let mut watcher = FileWatcher::new(Arc::new(sender), known.clone(), Arc::new(ProcessedCache::new()), Arc::new(RwLock::new(project)));
+		let event = |kind: EventKind, path: &Path| Ok(Event::new(kind).add_path(path.to_path_buf()));
+		let drain = |receiver: &mut tokio::sync::broadcast::Receiver<FileChanged>| {
+			std::thread::sleep(Duration::from_millis(150));
+			let mut events = Vec::new();
+			while let Ok(event) = receiver.try_recv() {
+				events.push(event);
+			}
+			events
+		};
+
+		// an editor saving by creating, truncating and rewriting is a single change
+		let file = root.join("a.lua");
+		std::fs::write(&file, "return 1").unwrap();
+		watcher.handle_event(event(EventKind::Create(CreateKind::File), &file));
+		watcher.handle_event(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &file));
+		watcher.handle_event(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &file));
+		let events = drain(&mut receiver);
+		assert!(matches!(events.as_slice(), [FileChanged::Changed { path }] if path == &file));
+		assert!(known.read().contains(&file));
+
+		// temp files that are gone again by the time they settle aren't sent at all
+		let temp = root.join("a.lua.swp");
+		watcher.handle_event(event(EventKind::Create(CreateKind::File), &temp));
+		watcher.handle_event(event(EventKind::Remove(RemoveKind::File), &temp));
+		assert!(drain(&mut receiver).is_empty());
+		assert!(!known.read().contains(&temp));
+
+		// files modified outside of any channel are ignored
+		let unknown = root.join("b.lua");
+		std::fs::write(&unknown, "return 2").unwrap();
+		watcher.handle_event(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &unknown));
+		assert!(drain(&mut receiver).is_empty());
+
+		std::fs::remove_file(&file).unwrap();
+		watcher.handle_event(event(EventKind::Remove(RemoveKind::File), &file));
+		let events = drain(&mut receiver);
+		assert!(matches!(events.as_slice(), [FileChanged::Deleted { path }] if path == &file));
+		assert!(!known.read().contains(&file));
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	// the directory, its watcher, what it sends and the files it knows about
+	type WatchedDir = (std::path::PathBuf, Box<dyn notify::Watcher + Send>, tokio::sync::broadcast::Receiver<FileChanged>, Arc<RwLock<HashSet<std::path::PathBuf>>>);
+
+	// a FileWatcher on a fresh temporary directory, the way the server sets one up
+	fn watch_temp_dir(watch_mode: WatchMode) -> WatchedDir {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		// watches report canonical paths, /tmp can be a symlink
+		let root = root.canonicalize().unwrap();
+		let mut project = test_project(8000, &root.to_string_lossy(), Vec::new());
+		project.debounce_ms = 50;
+		project.watch_mode = watch_mode;
+		project.poll_interval_ms = 50;
+		let (sender, receiver) = tokio::sync::broadcast::channel(100);
+		let known = Arc::new(RwLock::new(HashSet::new()));
+		let handler = FileWatcher::new(Arc::new(sender), known.clone(), Arc::new(ProcessedCache::new()), Arc::new(RwLock::new(project)));
+		let watcher = create_watcher(handler, &root, notify::RecursiveMode::Recursive, watch_mode, Duration::from_millis(50)).unwrap();
+		(root, watcher, receiver, known)
+	}
+
+	// waits for events to settle, sorted since the order of events for different paths isn't fixed
+	fn settled_events(receiver: &mut tokio::sync::broadcast::Receiver<FileChanged>) -> Vec<String> {
+		std::thread::sleep(Duration::from_millis(300));
+		let mut events = Vec::new();
+		while let Ok(event) = receiver.try_recv() {
+			events.push(match event {
+				FileChanged::Changed { path } => format!("changed {}", path.to_string_lossy()),
+				FileChanged::Deleted { path } => format!("deleted {}", path.to_string_lossy()),
+				FileChanged::Renamed { from, to } => format!("renamed {} {}", from.to_string_lossy(), to.to_string_lossy()),
+				FileChanged::ProjectReloaded { .. } => "reloaded".to_string()
+			});
+		}
+		events.sort();
+		events
+	}
+
+	#[test]
+	fn file_watcher_files() {
+		let (root, watcher, mut receiver, known) = watch_temp_dir(WatchMode::Native);
+		let file = root.join("a.lua");
+		let name = file.to_string_lossy().to_string();
+
+		std::fs::write(&file, "return 1").unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("changed {}", name)]);
+		assert!(known.read().contains(&file));
+
+		std::fs::write(&file, "return 2").unwrap();
+		std::fs::write(&file, "return 3").unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("changed {}", name)]);
+
+		let renamed = root.join("b.lua");
+		std::fs::rename(&file, &renamed).unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("renamed {} {}", name, renamed.to_string_lossy())]);
+
+		std::fs::remove_file(&renamed).unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("deleted {}", renamed.to_string_lossy())]);
+		assert!(known.read().is_empty());
+
+		// files that are gone before their events are handled are skipped instead of panicking the watcher
+		for i in 0..20 {
+			let temp = root.join(format!("temp{}", i));
+			std::fs::write(&temp, "").unwrap();
+			std::fs::remove_file(&temp).unwrap();
+		}
+		assert!(settled_events(&mut receiver).is_empty());
+		std::fs::write(&file, "return 4").unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("changed {}", name)]);
+
+		drop(watcher);
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn file_watcher_directories() {
+		let (root, watcher, mut receiver, known) = watch_temp_dir(WatchMode::Native);
+
+		// the files are written before the new directory is being watched
+		let created = root.join("new").join("nested");
+		std::fs::create_dir_all(&created).unwrap();
+		std::fs::write(created.join("a.lua"), "return 1").unwrap();
+		std::fs::write(root.join("new").join("b.lua"), "return 2").unwrap();
+		let mut expected = vec![format!("changed {}", created.join("a.lua").to_string_lossy()), format!("changed {}", root.join("new").join("b.lua").to_string_lossy())];
+		expected.sort();
+		assert_eq!(settled_events(&mut receiver), expected);
+
+		// directories moved in from outside never had their files watched at all
+		let outside = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(outside.join("deep")).unwrap();
+		std::fs::write(outside.join("deep").join("c.lua"), "return 3").unwrap();
+		let moved = root.join("moved");
+		std::fs::rename(&outside, &moved).unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("changed {}", moved.join("deep").join("c.lua").to_string_lossy())]);
+		assert_eq!(known.read().len(), 3);
+
+		// and moving one out takes its files along
+		std::fs::rename(&moved, &outside).unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("deleted {}", moved.join("deep").join("c.lua").to_string_lossy())]);
+		assert_eq!(known.read().len(), 2);
+
+		drop(watcher);
+		std::fs::remove_dir_all(&outside).unwrap();
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn file_watcher_polling() {
+		let (root, watcher, mut receiver, known) = watch_temp_dir(WatchMode::Poll);
+		let file = root.join("a.lua");
+		let name = file.to_string_lossy().to_string();
+
+		std::fs::create_dir_all(root.join("new")).unwrap();
+		std::fs::write(&file, "return 1").unwrap();
+		std::fs::write(root.join("new").join("b.lua"), "return 2").unwrap();
+		let mut expected = vec![format!("changed {}", name), format!("changed {}", root.join("new").join("b.lua").to_string_lossy())];
+		expected.sort();
+		assert_eq!(settled_events(&mut receiver), expected);
+		assert_eq!(known.read().len(), 2);
+
+		// same length and most likely the same mtime, only the contents tell them apart
+		std::fs::write(&file, "return 3").unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("changed {}", name)]);
+
+		std::fs::remove_dir_all(root.join("new")).unwrap();
+		assert_eq!(settled_events(&mut receiver), vec![format!("deleted {}", root.join("new").join("b.lua").to_string_lossy())]);
+		assert_eq!(known.read().len(), 1);
+
+		drop(watcher);
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn watch_modes() {
+		let missing = std::env::temp_dir().join(randstring(12));
+		let handler = |_: notify::Result<notify::Event>| {};
+		assert!(create_watcher(handler, &missing, notify::RecursiveMode::Recursive, WatchMode::Native, Duration::from_millis(50)).is_err());
+		let watch_mode: WatchMode = ron::from_str("poll").unwrap();
+		assert_eq!(watch_mode, WatchMode::Poll);
+		let mut project = test_project(8000, &std::env::temp_dir().to_string_lossy(), Vec::new());
+		assert!(validate_project(&project).is_ok());
+		project.poll_interval_ms = 0;
+		project.debounce_ms = 0;
+		assert_eq!(validate_project(&project).unwrap_err().len(), 2);
+	}
+
+	#[test]
+	fn rename_events() {
+		use notify::{Event, EventHandler, EventKind, event::{CreateKind, DataChange, ModifyKind, RenameMode}};
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(root.join("lib").join("sub")).unwrap();
+		let mut project = test_project(8000, &root.to_string_lossy(), vec![test_channel("lib")]);
+		project.debounce_ms = 50;
+		let project = Arc::new(RwLock::new(project));
+		let file = root.join("lib").join("a.lua");
+		let nested = root.join("lib").join("sub").join("b.lua");
+		std::fs::write(&file, "return 1").unwrap();
+		std::fs::write(&nested, "return 2").unwrap();
+		let (sender, mut receiver) = tokio::sync::broadcast::channel(100);
+		let known = Arc::new(RwLock::new(HashSet::from([file.clone(), nested.clone()])));
+		let mut watcher = FileWatcher::new(Arc::new(sender), known.clone(), Arc::new(ProcessedCache::new()), project.clone());
+		let event = |kind: EventKind, paths: &[&Path]| Ok(paths.iter().fold(Event::new(kind), |event, path| event.add_path(path.to_path_buf())));
+		// inotify sends both halves on their own before the combined event
+		let rename = |watcher: &mut FileWatcher, from: &Path, to: &Path| {
+			std::fs::rename(from, to).unwrap();
+			watcher.handle_event(event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &[from]));
+			watcher.handle_event(event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &[to]));
+			watcher.handle_event(event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &[from, to]));
+		};
+		let drain = |receiver: &mut tokio::sync::broadcast::Receiver<FileChanged>| {
+			std::thread::sleep(Duration::from_millis(150));
+			let mut events = Vec::new();
+			while let Ok(event) = receiver.try_recv() {
+				events.push(event);
+			}
+			events
+		};
+
+		let renamed = root.join("lib").join("c.lua");
+		rename(&mut watcher, &file, &renamed);
+		let events = drain(&mut receiver);
+		assert!(matches!(events.as_slice(), [FileChanged::Renamed { from, to }] if from == &file && to == &renamed));
+		assert!(known.read().contains(&renamed) && !known.read().contains(&file));
+
+		let moved_dir = root.join("lib").join("moved");
+		rename(&mut watcher, &root.join("lib").join("sub"), &moved_dir);
+		let events = drain(&mut receiver);
+		assert!(matches!(events.as_slice(), [FileChanged::Renamed { to, .. }] if to == &moved_dir));
+		assert!(known.read().contains(&moved_dir.join("b.lua")));
+
+		// editors that save by writing a temp file and moving it over the original only change the original
+		let temp = root.join("lib").join("c.lua.tmp");
+		std::fs::write(&temp, "return 3").unwrap();
+		watcher.handle_event(event(EventKind::Create(CreateKind::File), &[&temp]));
+		watcher.handle_event(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &[&temp]));
+		rename(&mut watcher, &temp, &renamed);
+		let events = drain(&mut receiver);
+		assert!(matches!(events.as_slice(), [FileChanged::Changed { path }] if path == &renamed));
+
+		// what subscriptions make of it
+		let channels = project.read().items.clone();
+		let outside = root.join("c.lua");
+		std::fs::rename(&renamed, &outside).unwrap();
+		assert_eq!(get_rename_changes(&project, &root, &channels, &renamed, &outside), RenameChanges { removed: vec!["lib/c.lua".to_string()], ..Default::default() });
+		std::fs::rename(&outside, &renamed).unwrap();
+		assert_eq!(get_rename_changes(&project, &root, &channels, &outside, &renamed), RenameChanges { changed: vec![(renamed.clone(), "lib".to_string())], ..Default::default() });
+		let renames = get_rename_changes(&project, &root, &channels, &root.join("lib").join("sub"), &moved_dir).renames;
+		assert_eq!(renames, vec![("lib/sub".to_string(), "lib/moved".to_string())]);
+
+		let encoded: Vec<u8> = RequestType::Rename { renames }.into();
+		assert_eq!(encoded, [&[3, 0, 0, 0, 1, 0, 0, 0, 7][..], b"lib/sub", &[0, 0, 0, 9], b"lib/moved"].concat());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn bundle_dependencies() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		let main = root.join("main.lua");
+		let lib = root.join("lib.lua");
+		std::fs::write(&main, "local lib = require(\"lib\")\nprint(lib.value)\n").unwrap();
+		std::fs::write(&lib, "return { value = 1 }\n").unwrap();
+		let cache = ProcessedCache::new();
+		let options = ProcessOptions { item_type: ProjectItemType::Library, minify: false, deflate: false, bundle: true, require_prefix: None, prefix_exclusions: None };
+
+		let first = String::from_utf8(cache.get_or_process(&main, &root, &options).unwrap()).unwrap();
+		assert!(first.contains("value = 1"));
+		assert!(cache.is_dependency(&lib));
+		assert_eq!(cache.dependents(&lib), vec![main.clone()]);
+		cache.get_or_process(&main, &root, &options).unwrap();
+		assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+		// only the required module changes, the bundle still has to be rebuilt
+		std::fs::write(&lib, "return { value = 2 }\n").unwrap();
+		let second = String::from_utf8(cache.get_or_process(&main, &root, &options).unwrap()).unwrap();
+		assert!(second.contains("value = 2"));
+		assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn cc_require() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(root.join("programs/shared/list")).unwrap();
+		std::fs::create_dir_all(root.join("lib")).unwrap();
+		let main = root.join("programs/main.lua");
+		std::fs::write(&main, "local util = require(\"util\")\nlocal list = require \"shared.list\"\nlocal strings = require(\"/lib.strings\")\nlocal expect = require(\"cc.expect\")\n").unwrap();
+		// requires inside modules are still relative to the program, not the module
+		std::fs::write(root.join("programs/util.lua"), "return require(\"shared.list\")\n").unwrap();
+		std::fs::write(root.join("programs/shared/list/init.lua"), "return {}\n").unwrap();
+		std::fs::write(root.join("lib/strings.lua"), "return {}\n").unwrap();
+		let cache = ProcessedCache::new();
+		let options = ProcessOptions { item_type: ProjectItemType::Script, minify: false, deflate: false, bundle: true, require_prefix: None, prefix_exclusions: None };
+
+		let bundled = String::from_utf8(cache.get_or_process(&main, &root, &options).unwrap()).unwrap();
+		assert!(bundled.contains("__ccsync_paths[\"util\"] = \"programs/util.lua\""));
+		assert!(bundled.contains("__ccsync_paths[\"shared.list\"] = \"programs/shared/list/init.lua\""));
+		assert!(bundled.contains("__ccsync_paths[\"/lib.strings\"] = \"lib/strings.lua\""));
+		assert_eq!(bundled.matches("__ccsync_modules[\"programs/shared/list/init.lua\"] = function(...)").count(), 1);
+		assert!(bundled.contains("require(\"cc.expect\")"));
+
+		let missing = root.join("programs/missing.lua");
+		std::fs::write(&main, "local missing = require(\"missing\")\n").unwrap();
+		let errors = cache.get_or_process(&main, &root, &options).unwrap_err();
+		assert!(errors[0].contains("module 'missing' not found"));
+
+		// creating the module it looked for fixes the bundle without touching the file itself
+		assert_eq!(cache.dependents(&missing), vec![main.clone()]);
+		std::fs::write(&missing, "return 1\n").unwrap();
+		assert!(cache.get_or_process(&main, &root, &options).is_ok());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn channel_output() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(root.join("lib")).unwrap();
+		std::fs::create_dir_all(root.join("app")).unwrap();
+		std::fs::write(root.join("lib/a.lua"), "local value = 1 -- comment\nreturn value\n").unwrap();
+		std::fs::write(root.join("app/main.lua"), "print(require(\"lib.a\"))\n").unwrap();
+		let options = ron::Options::default()
+			.with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+		let project: Project = options.from_str(&format!(r#"Project(root_dir: "{}", max_uncompressed_request_size: 1000, port: 8002, minify: true, items: [
+			ProjectItem(type: Library, channel_name: "lib", directories: [Directory(path: "lib")]),
+			ProjectItem(type: Script, channel_name: "app", required_channels: ["lib"], files: [File(path: "app/main.lua", cc_path: "/startup.lua")]),
+			ProjectItem(type: Script, channel_name: "unrelated", files: [File(path: "app/main.lua", cc_path: "other.lua")]),
+		])"#, root.to_string_lossy())).unwrap();
+		let project = Arc::new(RwLock::new(project));
+		let cache = ProcessedCache::new();
+
+		let files = get_channel_output(&project, &["app".to_string()], &cache).unwrap();
+		let paths: HashSet<&str> = files.iter().map(|e| e.file_path.as_str()).collect();
+		assert_eq!(paths, HashSet::from(["lib/a.lua", "/startup.lua"]));
+		let library = files.iter().find(|e| e.file_path == "lib/a.lua").unwrap();
+		assert!(!String::from_utf8_lossy(&library.file_data).contains("comment"));
+		assert_eq!(library.file_hash, get_content_hash(&library.file_data));
+
+		assert_eq!(get_channel_output(&project, &["missing".to_string()], &cache).unwrap_err().len(), 1);
+
+		let out = Path::new("/out");
+		assert_eq!(get_export_path(out, "/startup.lua"), Some(out.join("startup.lua")));
+		assert_eq!(get_export_path(out, "lib/./a.lua"), Some(out.join("lib/a.lua")));
+		assert_eq!(get_export_path(out, "../escape.lua"), None);
+		assert_eq!(get_export_path(out, "/"), None);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn installer() {
+		let files = vec![
+			test_sync("/startup.lua", b"print('hi')".to_vec()),
+			test_sync("lib/sound.dfpwm", (0..=255).cycle().take(5000).collect())
+		];
+		// fs only has to record what was written, and lz4 needs cc's bit32
+		let mock_fs = "bit32 = { band = function(a, b) return a & b end, lshift = function(a, n) return (a << n) & 0xffffffff end, rshift = function(a, n) return (a & 0xffffffff) >> n end } __written = {} fs = { getDir = function(p) return p:match(\"^(.*)/[^/]*$\") or \"\" end, exists = function() return false end, makeDir = function() end, open = function(p) return { write = function(d) __written[p] = d end, close = function() end } end }";
+		for compression in [InstallerCompression::Lz4, InstallerCompression::Deflate] {
+			let script = build_installer(&files, &["app".to_string()], compression);
+			let lua = mlua::Lua::new();
+			lua.load("print = function() end").exec().unwrap();
+			lua.load(mock_fs).exec().unwrap();
+			lua.load(&script).exec().unwrap();
+			let written: HashMap<String, mlua::String> = lua.load("return __written").eval().unwrap();
+			assert_eq!(written.len(), 2);
+			for file in &files {
+				assert_eq!(written[&file.file_path].as_bytes(), &file.file_data[..]);
+			}
+		}
+
+		// deflate_trickery output needs the libraries sync.lua would have downloaded
+		let deflated = vec![test_sync("a.lua", b"return require(\"/cc-sync/libdeflate\")".to_vec())];
+		let script = build_installer(&deflated, &["app".to_string()], InstallerCompression::Lz4);
+		let lua = mlua::Lua::new();
+		lua.load("print = function() end").exec().unwrap();
+		lua.load(mock_fs).exec().unwrap();
+		lua.load(&script).exec().unwrap();
+		let written: HashMap<String, mlua::String> = lua.load("return __written").eval().unwrap();
+		assert!(written.contains_key("cc-sync/libdeflate.lua") && written.contains_key("cc-sync/base85.lua"));
+	}
+
+	// runs lua the same way for every version of a file, returning everything it printed
+	fn run_lua(code: &str, package_path: &str) -> String {
+		let lua = mlua::Lua::new();
+		lua.load(format!("package.path = \"{}\"", package_path)).exec().unwrap();
+		lua.load("__output = {} print = function(...) local t = table.pack(...) for i = 1, t.n do t[i] = tostring(t[i]) end __output[#__output + 1] = table.concat(t, \"\\t\") end").exec().unwrap();
+		lua.load(code).exec().unwrap();
+		lua.load("return table.concat(__output, \"\\n\")").eval::<String>().unwrap()
+	}
+
+	const GOTO_SOURCE: &str = r#"local out = {}
+for i = 1, 5 do
+	if i % 2 == 0 then goto continue end
+	out[#out + 1] = "odd " .. i
+	::continue::
+end
+local s = "goto nowhere ::not_a_label::" -- goto x
+--[[ ::also_not_a_label:: goto x ]]
+for i = 1, 3 do for j = 1, 3 do
+	if i * j == 4 then goto
+		done
+	end
+	out[#out + 1] = i .. "x" .. j
+end end
+::done:: local n = 0 :: again :: n = n + 1 if n < 3 then goto again end
+out[#out + 1] = s .. n
+print(table.concat(out, ","))
+"#;
+
+	#[test]
+	fn gotos() {
+		let root = std::env::temp_dir().join(randstring(12));
+		std::fs::create_dir_all(&root).unwrap();
+		let main = root.join("main.lua");
+		std::fs::write(&main, GOTO_SOURCE).unwrap();
+		std::fs::write(root.join("loops.lua"), format!("return function()\n{}\nend\n", GOTO_SOURCE)).unwrap();
+		let options = ProcessOptions { item_type: ProjectItemType::Script, minify: true, deflate: false, bundle: false, require_prefix: None, prefix_exclusions: None };
+		let package_path = format!("{}/?.lua", root.to_string_lossy());
+		let expected = run_lua(GOTO_SOURCE, &package_path);
+
+		let minified = String::from_utf8(process_file(&main, &root, &options).content.unwrap()).unwrap();
+		assert!(minified.len() < GOTO_SOURCE.len());
+		assert_eq!(run_lua(&minified, &package_path), expected);
+
+		let entry = format!("local loops = require(\"loops\")\nloops()\n{}", GOTO_SOURCE);
+		std::fs::write(&main, &entry).unwrap();
+		let expected = run_lua(&entry, &package_path);
+		for minify in [false, true] {
+			let bundled = String::from_utf8(process_file(&main, &root, &ProcessOptions { bundle: true, minify, ..options.clone() }).content.unwrap()).unwrap();
+			// nothing can be required from an empty package.path, so this only passes if the module was inlined
+			assert_eq!(run_lua(&bundled, ""), expected);
+		}
+
+		assert!(encode_gotos("__CCSYNC_GOTO(\"x\")").is_err());
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -1,11 +1,12 @@
-use std::{collections::HashSet, env, io::Write, path::{Path, PathBuf}, sync::Arc};
+use std::{collections::{HashMap, HashSet}, env, io::Write, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU32, Ordering}}};
 
-use darklua_core::{BundleConfiguration, Configuration, Options, Resources, rules::{ComputeExpression, FilterAfterEarlyReturn, GroupLocalAssignment, PathRequireMode, RemoveComments, RemoveEmptyDo, RemoveFunctionCallParens, RemoveIfExpression, RemoveMethodDefinition, RemoveNilDeclaration, RemoveSpaces, RemoveTypes, RemoveUnusedVariable, RemoveUnusedWhile, RenameVariables, Rule, bundle::BundleRequireMode}};
+use darklua_core::{Configuration, Options, Resources, rules::{ComputeExpression, FilterAfterEarlyReturn, GroupLocalAssignment, RemoveComments, RemoveEmptyDo, RemoveFunctionCallParens, RemoveIfExpression, RemoveMethodDefinition, RemoveNilDeclaration, RemoveSpaces, RemoveTypes, RemoveUnusedVariable, RemoveUnusedWhile, RenameVariables, Rule}};
 use flate2::{Compression, write::DeflateEncoder};
-use lazy_regex::regex_replace_all;
 use parking_lot::RwLock;
+use rapidhash::v3::rapidhash_v3;
+use tokio::sync::watch;
 
-use crate::{rules::prefix_requires::PrefixRequireRule, structs::{DataSync, Directory, File, Project, ProjectItem, ProjectItemType, RequestType}};
+use crate::{rules::{cc_require::{CcRequireRule, RequireState}, gotos::{decode_gotos, encode_gotos}, prefix_requires::PrefixRequireRule}, server::cache::{ProcessOptions, ProcessedCache}, structs::{DataSync, Directory, File, ManifestEntry, PROTOCOL_VERSION, Project, ProjectItem, ProjectItemType, RequestType}};
 
 pub struct FileBatcher {
 	pub currently_in: Vec<RequestType>
@@ -25,26 +26,6 @@ impl FileBatcher {
 	}
 }
 
-pub fn chunk_string(s: &str, chunk_size: usize) -> Vec<&str> {
-	let mut chunks = Vec::new();
-	let mut char_iter = s.char_indices().peekable();
-	
-	while char_iter.peek().is_some() {
-		let start = char_iter.next().unwrap().0;
-			
-		for _ in 0..chunk_size - 1 {
-			if char_iter.next().is_none() {
-				break;
-			}
-		}
-			
-		let end = char_iter.peek().map(|(pos, _)| *pos).unwrap_or(s.len());
-		chunks.push(&s[start..end]);
-	}
-	
-	chunks
-}
-
 pub fn get_associated_item(project: &Arc<RwLock<Project>>, file: &PathBuf, channel: &String) -> Option<(Option<Directory>, Option<File>, ProjectItem)> {
 	let r = project.read();
 	let items = &r.items;
@@ -73,6 +54,19 @@ pub fn get_associated_item(project: &Arc<RwLock<Project>>, file: &PathBuf, chann
 	return None;
 }
 
+// ids only have to be unique among the transfers a computer hasn't finished yet, so one counter for every subscription is plenty.
+// 0 is never handed out, it marks files that fit in a single piece
+static NEXT_TRANSFER_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_transfer_id() -> u32 {
+	loop {
+		let id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+		if id != 0 {
+			return id;
+		}
+	}
+}
+
 pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize) -> Vec<Vec<RequestType>> {
 	let mut res: Vec<Vec<RequestType>> = Vec::new();
 	let mut current_set: Vec<RequestType> = Vec::new();
@@ -88,12 +82,14 @@ pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize
 					}
 					current_size = 0;
 					if len > max_uncompressed_request_size {
-						let chunked = chunk_string(&data.file_data, max_uncompressed_request_size);
-						current_set.push(RequestType::Resource { data: DataSync { file_path: data.file_path, file_data: chunked[0].to_string() } });
+						let chunked: Vec<&[u8]> = data.file_data.chunks(max_uncompressed_request_size).collect();
+						let transfer_id = next_transfer_id();
+						let total = chunked.len() as u32;
+						current_set.push(RequestType::Resource { data: DataSync { file_data: chunked[0].to_vec(), transfer_id, ..data } });
 						res.push(current_set);
 						current_set = Vec::new();
-						for chunk in chunked.iter().skip(1) {
-							current_set.push(RequestType::Chunk { file_data: chunk.to_string() });
+						for (index, chunk) in chunked.iter().enumerate().skip(1) {
+							current_set.push(RequestType::Chunk { transfer_id, index: index as u32, total, file_data: chunk.to_vec() });
 							if current_size + chunk.len() >= max_uncompressed_request_size {
 								res.push(current_set);
 								current_set = Vec::new();
@@ -123,12 +119,14 @@ pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize
 					}
 					current_size = 0;
 					if len > max_uncompressed_request_size {
-						let chunked = chunk_string(&data.file_data, max_uncompressed_request_size);
-						current_set.push(RequestType::Library { data: DataSync { file_path: data.file_path, file_data: chunked[0].to_string() } });
+						let chunked: Vec<&[u8]> = data.file_data.chunks(max_uncompressed_request_size).collect();
+						let transfer_id = next_transfer_id();
+						let total = chunked.len() as u32;
+						current_set.push(RequestType::Library { data: DataSync { file_data: chunked[0].to_vec(), transfer_id, ..data } });
 						res.push(current_set);
 						current_set = Vec::new();
-						for chunk in chunked.iter().skip(1) {
-							current_set.push(RequestType::Chunk { file_data: chunk.to_string() });
+						for (index, chunk) in chunked.iter().enumerate().skip(1) {
+							current_set.push(RequestType::Chunk { transfer_id, index: index as u32, total, file_data: chunk.to_vec() });
 							if current_size + chunk.len() >= max_uncompressed_request_size {
 								res.push(current_set);
 								current_set = Vec::new();
@@ -158,12 +156,14 @@ pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize
 					}
 					current_size = 0;
 					if len > max_uncompressed_request_size {
-						let chunked = chunk_string(&data.file_data, max_uncompressed_request_size);
-						current_set.push(RequestType::Script { data: DataSync { file_path: data.file_path, file_data: chunked[0].to_string() } });
+						let chunked: Vec<&[u8]> = data.file_data.chunks(max_uncompressed_request_size).collect();
+						let transfer_id = next_transfer_id();
+						let total = chunked.len() as u32;
+						current_set.push(RequestType::Script { data: DataSync { file_data: chunked[0].to_vec(), transfer_id, ..data } });
 						res.push(current_set);
 						current_set = Vec::new();
-						for chunk in chunked.iter().skip(1) {
-							current_set.push(RequestType::Chunk { file_data: chunk.to_string() });
+						for (index, chunk) in chunked.iter().enumerate().skip(1) {
+							current_set.push(RequestType::Chunk { transfer_id, index: index as u32, total, file_data: chunk.to_vec() });
 							if current_size + chunk.len() >= max_uncompressed_request_size {
 								res.push(current_set);
 								current_set = Vec::new();
@@ -203,6 +203,10 @@ pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize
 				}
 				current_set.push(RequestType::Deletion { files: del_vec });
 			},
+			RequestType::Rename { renames } => {
+				current_size += renames.iter().map(|(from, to)| from.len() + to.len()).sum::<usize>();
+				current_set.push(RequestType::Rename { renames });
+			},
 			_ => panic!("there should be no chunks inserted already")
 		}
 	}
@@ -213,6 +217,62 @@ pub fn chunk_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize
 	res
 }
 
+pub fn encode_batch(batch: Vec<RequestType>, max_uncompressed_request_size: usize) -> Vec<Vec<u8>> {
+	let merged = merge(batch);
+	let chunked = chunk_batch(merged, max_uncompressed_request_size);
+	let mut messages = Vec::new();
+	for chunk in chunked {
+		let bytes = {
+			let mut v = Vec::new();
+			for req in chunk {
+				v.extend_from_slice(&<RequestType as Into<Vec<u8>>>::into(req));
+			}
+			v
+		};
+		let mut deflate = DeflateEncoder::new(vec![], Compression::best());
+		deflate.write_all(&bytes).unwrap();
+		messages.push(deflate.finish().unwrap());
+	}
+	messages
+}
+
+pub fn get_content_hash(content: &[u8]) -> String {
+	format!("{:016x}", rapidhash_v3(content))
+}
+
+// adler32, the same as libDeflate:Adler32
+pub fn get_checksum(content: &[u8]) -> u32 {
+	let mut a: u32 = 1;
+	let mut b: u32 = 0;
+	for byte in content {
+		a = (a + *byte as u32) % 65521;
+		b = (b + a) % 65521;
+	}
+	(b << 16) | a
+}
+
+// drops files the client already has an identical copy of, and deletes files it got from this project
+// that no channel owns anymore
+pub fn apply_manifest(batch: Vec<RequestType>, manifest: &HashMap<String, ManifestEntry>, owned: &HashSet<String>) -> Vec<RequestType> {
+	let mut res = Vec::new();
+	for item in batch {
+		let data = match &item {
+			RequestType::Resource { data } | RequestType::Library { data } | RequestType::Script { data } => Some(data),
+			_ => None
+		};
+		if let Some(data) = data && let Some(entry) = manifest.get(&data.file_path) && entry.hash == data.file_hash && entry.size == data.file_data.len() as u64 {
+			continue;
+		}
+		res.push(item);
+	}
+	let mut removed: Vec<String> = manifest.keys().filter(|e| !owned.contains(*e)).cloned().collect();
+	if !removed.is_empty() {
+		removed.sort();
+		res.push(RequestType::Deletion { files: removed });
+	}
+	res
+}
+
 pub fn merge(batch: Vec<RequestType>) -> Vec<RequestType> {
 	let mut res = Vec::new();
 	let mut del_vec: Vec<String> = Vec::new();
@@ -238,104 +298,46 @@ pub fn merge(batch: Vec<RequestType>) -> Vec<RequestType> {
 	res
 }
 
-pub fn process_file(file: &PathBuf, root: &PathBuf, item_type: ProjectItemType, minify: bool, deflate: bool, bundle: bool, require_prefix: Option<String>, prefix_exclusions: Option<Vec<String>>) -> String {
-	let file_bytes = std::fs::read(file).unwrap();
-	let mut content = String::from_utf8(file_bytes).unwrap();
+pub struct ProcessedFile {
+	pub content: Result<Vec<u8>, Vec<String>>,
+	// files the output was built from besides the file itself, so far only set when bundling
+	pub dependencies: Vec<PathBuf>
+}
+
+pub fn process_file(file: &Path, root: &Path, options: &ProcessOptions) -> ProcessedFile {
+	let mut dependencies = Vec::new();
+	let content = process_content(file, root, options, &mut dependencies);
+	dependencies.sort();
+	dependencies.dedup();
+	ProcessedFile { content, dependencies }
+}
+
+fn process_content(file: &Path, root: &Path, options: &ProcessOptions, dependencies: &mut Vec<PathBuf>) -> Result<Vec<u8>, Vec<String>> {
+	let ProcessOptions { item_type, minify, deflate, bundle, require_prefix, prefix_exclusions } = options.clone();
+	let mut file_bytes = std::fs::read(file).map_err(|e| vec![format!("failed to read {}: {}", file.to_string_lossy(), e)])?;
+	// resources are synced byte for byte, only lua has to be text
 	if item_type != ProjectItemType::Resource {
+		let mut content = String::from_utf8(file_bytes).map_err(|e| vec![format!("{} is not valid utf-8: {}", file.to_string_lossy(), e)])?;
 		if let Some(pfx) = require_prefix.clone() {
-			// manually comment out gotos so darklua's parser doesnt screw up
-			comment_gotos(&mut content);
-			let mut base_exclude = vec!["cc.audio.dfpwm".to_string(), "cc.completion".to_string(), "cc.expect".to_string(), "cc.image.nft".to_string(), "cc.pretty".to_string(), "cc.require".to_string(), "cc.shell.completion".to_string(), "cc.strings".to_string()];
-			if let Some(mut exc) = prefix_exclusions.clone() {
-				base_exclude.append(&mut exc);
-			}
 			let rule: Box<dyn Rule> = Box::new(PrefixRequireRule::new(
 				pfx, 
-				base_exclude
+				get_require_exclusions(&prefix_exclusions)
 			));
 			let cfg = Configuration::empty()
 				.with_rule(rule);
-			let resources = Resources::from_memory();
-			resources.write(file.file_name().unwrap(), &content).unwrap();
-			darklua_core::process(&resources, Options::new(Path::new(file.file_name().unwrap())).with_configuration(cfg)).unwrap().result().unwrap();
-			content = resources.get(Path::new(file.file_name().unwrap())).unwrap();
-			// manually uncomment the commented gotos (hopefully keeping everything functional)
-			uncomment_gotos(&mut content);
+			content = run_darklua(Path::new(file.file_name().unwrap()), &content, cfg)?;
 		}
 		if bundle {
-			// manually comment out gotos so darklua's parser doesnt screw up
-			comment_gotos(&mut content);
-			let mut cfg = Configuration::empty();
-			let resources = Resources::from_memory();
-			cfg = cfg.with_bundle_configuration(
-				BundleConfiguration::new(
-					BundleRequireMode::Path(
-						PathRequireMode::new("")
-					)
-				)
-				.with_exclude("cc.audio.dfpwm")
-				.with_exclude("cc.completion")
-				.with_exclude("cc.expect")
-				.with_exclude("cc.image.nft")
-				.with_exclude("cc.pretty")
-				.with_exclude("cc.require")
-				.with_exclude("cc.shell.completion")
-				.with_exclude("cc.strings")
-			);
-			for file in walkdir::WalkDir::new(root) {
-				if let Ok(entry) = file {
-					if let Ok(metadata) = entry.metadata() {
-						if metadata.is_file() {
-							let path = entry.into_path();
-							let relative_to_root = path.strip_prefix(root).unwrap();
-							let str = relative_to_root.to_string_lossy().to_string().replace("/", ".");
-							if let Some(pfx) = require_prefix.clone() {
-								let file_content = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
-								let mut base_exclude = vec!["cc.audio.dfpwm".to_string(), "cc.completion".to_string(), "cc.expect".to_string(), "cc.image.nft".to_string(), "cc.pretty".to_string(), "cc.require".to_string(), "cc.shell.completion".to_string(), "cc.strings".to_string()];
-								if let Some(mut exc) = prefix_exclusions.clone() {
-									base_exclude.append(&mut exc);
-								}
-								let rule: Box<dyn Rule> = Box::new(PrefixRequireRule::new(
-									pfx.clone(), 
-									base_exclude
-								));
-								let cfg = Configuration::empty()
-									.with_rule(rule);
-								let res = Resources::from_memory();
-								res.write(path.file_name().unwrap(), &file_content
-								.replace("::__continue", "-- ::__continue")
-								.replace("goto __continue", "continue -- goto __continue")).unwrap();
-								darklua_core::process(&res, Options::new(Path::new(path.file_name().unwrap())).with_configuration(cfg)).unwrap().result().unwrap();
-								resources.write(pfx + &str, &res.get(Path::new(path.file_name().unwrap())).unwrap()).unwrap();
-							}
-							else {
-								resources.write(&str, &String::from_utf8(std::fs::read(&path).unwrap()).unwrap()).unwrap();
-							}
-						}
-					}
-				}
-			}
-			resources.write(file.file_name().unwrap(), &content).unwrap();
-			darklua_core::process(&resources, Options::new(Path::new(file.file_name().unwrap())).with_configuration(cfg)).unwrap().result().unwrap();
-			content = resources.get(Path::new(file.file_name().unwrap())).unwrap();
-			// manually uncomment the commented gotos (hopefully keeping everything functional)
-			uncomment_gotos(&mut content);
+			content = bundle_file(file, root, content, &require_prefix, &prefix_exclusions, dependencies)?;
 		}
 		if minify {
-			// manually comment out gotos so darklua's parser doesnt screw up
-			comment_gotos(&mut content);
-			let cfg = get_darklua_cfg();
-			let resources = Resources::from_memory();
-			resources.write(file.file_name().unwrap(), &content).unwrap();
-			darklua_core::process(&resources, Options::new(Path::new(file.file_name().unwrap())).with_configuration(cfg)).unwrap().result().unwrap();
-			content = resources.get(Path::new(file.file_name().unwrap())).unwrap();
-			// manually uncomment the commented gotos (hopefully keeping everything functional)
-			uncomment_gotos(&mut content);
+			content = run_darklua(Path::new(file.file_name().unwrap()), &content, get_darklua_cfg())?;
 		}
+		file_bytes = content.into_bytes();
 	}
 	if deflate {
 		let mut encoder = DeflateEncoder::new(vec![], Compression::best());
-		encoder.write(content.as_bytes()).unwrap();
+		encoder.write_all(&file_bytes).unwrap();
 		let res = encoder.finish().unwrap();
 		let str = base85::encode(&res);
 		let c_str;
@@ -345,11 +347,127 @@ pub fn process_file(file: &PathBuf, root: &PathBuf, item_type: ProjectItemType,
 		else {
 			c_str = format!("return load(require(\"/cc-sync/libdeflate\").libDeflate:DecompressDeflate(select(2, require(\"/cc-sync/base85\").decode(\"{}\"))))(...)", str);
 		}
-		if c_str.len() < content.len() {
-			content = c_str;
+		if c_str.len() < file_bytes.len() {
+			file_bytes = c_str.into_bytes();
 		}
 	}
-	content
+	Ok(file_bytes)
+}
+
+// modules that are never prefixed and are left for the computer to require when bundling
+fn get_require_exclusions(prefix_exclusions: &Option<Vec<String>>) -> Vec<String> {
+	let mut base_exclude = vec!["cc.audio.dfpwm".to_string(), "cc.completion".to_string(), "cc.expect".to_string(), "cc.image.nft".to_string(), "cc.pretty".to_string(), "cc.require".to_string(), "cc.shell.completion".to_string(), "cc.strings".to_string()];
+	if let Some(exc) = prefix_exclusions {
+		base_exclude.extend(exc.iter().cloned());
+	}
+	base_exclude
+}
+
+fn run_darklua(name: &Path, content: &str, cfg: Configuration) -> Result<String, Vec<String>> {
+	let content = encode_gotos(content).map_err(|e| vec![format!("{}: {}", name.to_string_lossy(), e)])?;
+	let resources = Resources::from_memory();
+	resources.write(name, &content).unwrap();
+	darklua_core::process(&resources, Options::new(name).with_configuration(cfg))
+		.map_err(|e| vec![e.to_string()])?
+		.result()
+		.map_err(|errors| errors.iter().map(|e| e.to_string()).collect::<Vec<String>>())?;
+	Ok(decode_gotos(&resources.get(name).unwrap()))
+}
+
+const BUNDLE_RUNTIME: &str = r#"local __ccsync_modules, __ccsync_paths, __ccsync_sentinel = {}, {}, {}
+local function __ccsync_require(name)
+	local loaded = package.loaded[name]
+	if loaded == __ccsync_sentinel then
+		error("loop or previous error loading module '" .. name .. "'", 0)
+	elseif loaded ~= nil then
+		return loaded
+	end
+	local path = __ccsync_paths[name]
+	package.loaded[name] = __ccsync_sentinel
+	local result = __ccsync_modules[path](name, path)
+	if result == nil then
+		result = true
+	end
+	package.loaded[name] = result
+	return result
+end
+"#;
+
+// inlines every module the file requires, resolved the same way cc.require would resolve them on the computer
+// (relative to the directory the file is synced to, with the project root as the computer's root)
+fn bundle_file(file: &Path, root: &Path, content: String, require_prefix: &Option<String>, prefix_exclusions: &Option<Vec<String>>, dependencies: &mut Vec<PathBuf>) -> Result<String, Vec<String>> {
+	let program_dir = file.parent().and_then(|e| e.strip_prefix(root).ok()).map(|e| e.to_string_lossy().replace("\\", "/")).unwrap_or_default();
+	let exclusions = get_require_exclusions(prefix_exclusions);
+	let resolve = |name: &Path, source: &str, prefix: Option<String>, dependencies: &mut Vec<PathBuf>| {
+		let state = Arc::new(parking_lot::Mutex::new(RequireState::default()));
+		let mut cfg = Configuration::empty();
+		if let Some(pfx) = prefix {
+			let rule: Box<dyn Rule> = Box::new(PrefixRequireRule::new(pfx, exclusions.clone()));
+			cfg = cfg.with_rule(rule);
+		}
+		let rule: Box<dyn Rule> = Box::new(CcRequireRule::new(root.to_path_buf(), program_dir.clone(), exclusions.clone(), state.clone()));
+		cfg = cfg.with_rule(rule);
+		let result = run_darklua(name, source, cfg);
+		let state = std::mem::take(&mut *state.lock());
+		dependencies.extend(state.searched);
+		result.map(|e| (e, state.resolved))
+	};
+	// the file itself already had require_prefix applied
+	let (entry, mut pending) = resolve(Path::new(file.file_name().unwrap()), &content, None, dependencies)?;
+	let mut errors = Vec::new();
+	let mut modules: Vec<(String, String)> = Vec::new();
+	let mut names: Vec<(String, String)> = Vec::new();
+	let mut visited: HashSet<String> = HashSet::new();
+	while let Some(module) = pending.pop() {
+		if !names.iter().any(|(name, _)| *name == module.name) {
+			names.push((module.name.clone(), module.cc_path.clone()));
+		}
+		if !visited.insert(module.cc_path.clone()) {
+			continue;
+		}
+		let source = match std::fs::read(&module.path).map(String::from_utf8) {
+			Ok(Ok(source)) => source,
+			_ => {
+				errors.push(format!("failed to read module {}", module.path.to_string_lossy()));
+				continue;
+			}
+		};
+		match resolve(Path::new(&module.cc_path), &source, require_prefix.clone(), dependencies) {
+			Ok((processed, mut required)) => {
+				modules.push((module.cc_path, processed));
+				pending.append(&mut required);
+			}
+			Err(mut e) => errors.append(&mut e)
+		}
+	}
+	if !errors.is_empty() {
+		return Err(errors);
+	}
+	let mut bundled = BUNDLE_RUNTIME.to_string();
+	modules.sort();
+	for (cc_path, source) in modules {
+		bundled += &format!("__ccsync_modules[{}] = function(...)\n{}\nend\n", lua_string(&cc_path), source.trim_end());
+	}
+	names.sort();
+	for (name, cc_path) in names {
+		bundled += &format!("__ccsync_paths[{}] = {}\n", lua_string(&name), lua_string(&cc_path));
+	}
+	bundled += &entry;
+	Ok(bundled)
+}
+
+fn lua_string(s: &str) -> String {
+	let mut res = String::from("\"");
+	for b in s.bytes() {
+		match b {
+			b'"' => res += "\\\"",
+			b'\\' => res += "\\\\",
+			0x20..=0x7e => res.push(b as char),
+			_ => res += &format!("\\{:03}", b)
+		}
+	}
+	res.push('"');
+	res
 }
 
 pub fn get_full_channel_list(channels: Vec<String>, project: &Arc<RwLock<Project>>, encountered: &mut HashSet<String>) -> Vec<ProjectItem> {
@@ -377,7 +495,75 @@ pub fn get_full_channel_list(channels: Vec<String>, project: &Arc<RwLock<Project
 	res
 }
 
-pub fn get_files_for_channel(root: &PathBuf, channel: &ProjectItem) -> Vec<PathBuf> {
+// what a client has to be sent when its channels go from old_list to new_list. paths no remaining channel has are deleted,
+// channels that are new to it or listed in resend get fully sent since any of their files' output may differ
+pub fn get_channel_changes(old_root: &Path, old_list: &[ProjectItem], new_root: &Path, new_list: &[ProjectItem], resend: &[String]) -> (Vec<String>, Vec<(PathBuf, String)>) {
+	let old_paths = get_cc_paths_for_channels(old_root, old_list);
+	let new_paths = get_cc_paths_for_channels(new_root, new_list);
+	let removed: Vec<String> = old_paths.difference(&new_paths).cloned().collect();
+	let mut files = Vec::new();
+	for channel in new_list {
+		let was_subscribed = old_list.iter().any(|e| e.channel_name == channel.channel_name);
+		if was_subscribed && !resend.contains(&channel.channel_name) {
+			continue;
+		}
+		for file in get_files_for_channel(new_root, channel) {
+			files.push((file, channel.channel_name.clone()));
+		}
+	}
+	(removed, files)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RenameChanges {
+	// old and new path on the computer
+	pub renames: Vec<(String, String)>,
+	pub removed: Vec<String>,
+	// files to send along with the channel they're synced through
+	pub changed: Vec<(PathBuf, String)>
+}
+
+// what moving from to to on disk means for a computer subscribed to channels. files synced the same way at both paths are moved
+// on the computer too, a directory at once when all of its files are, anything else is deleted or sent like a new file
+pub fn get_rename_changes(project: &Arc<RwLock<Project>>, root: &Path, channels: &[ProjectItem], from: &Path, to: &Path) -> RenameChanges {
+	let files: Vec<PathBuf> = if to.is_dir() {
+		walkdir::WalkDir::new(to).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).map(|e| e.into_path()).collect()
+	}
+	else {
+		vec![to.to_path_buf()]
+	};
+	let mut renames = Vec::new();
+	let mut removed = Vec::new();
+	let mut changed = Vec::new();
+	for file in files {
+		let old = if file == to { from.to_path_buf() } else { from.join(file.strip_prefix(to).unwrap()) };
+		for channel in channels {
+			let old_item = get_associated_item(project, &old, &channel.channel_name);
+			let new_item = get_associated_item(project, &file, &channel.channel_name);
+			// files listed on their own are only synced under the path they're listed with
+			if old_item.is_some() && old_item == new_item && old_item.as_ref().is_some_and(|e| e.1.is_none()) {
+				renames.push((get_cc_path(root, channel, &old), get_cc_path(root, channel, &file)));
+				continue;
+			}
+			if old_item.is_some() {
+				removed.push(get_cc_path(root, channel, &old));
+			}
+			if new_item.is_some() {
+				changed.push((file.clone(), channel.channel_name.clone()));
+			}
+		}
+	}
+	renames.sort();
+	renames.dedup();
+	// directories are only synced through directory entries, which keep paths relative to root
+	if to.is_dir() && !renames.is_empty() && removed.is_empty() && changed.is_empty() {
+		let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_string_lossy().to_string();
+		renames = vec![(relative(from), relative(to))];
+	}
+	RenameChanges { renames, removed, changed }
+}
+
+pub fn get_files_for_channel(root: &Path, channel: &ProjectItem) -> Vec<PathBuf> {
 	let mut v: Vec<PathBuf> = Vec::new();
 	let mut discovered: HashSet<PathBuf> = HashSet::new();
 	if let Some(dirs) = &channel.directories {
@@ -404,7 +590,244 @@ pub fn get_files_for_channel(root: &PathBuf, channel: &ProjectItem) -> Vec<PathB
 	v
 }
 
-pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>, batcher: &mut FileBatcher, project_minify: &Option<bool>, project_deflate: &Option<bool>, project_prefix: &Option<String>, project_prefix_exclude: &Option<Vec<String>>, path: &PathBuf, project_root: &PathBuf) {
+// everything a computer subscribing to these channels gets sent on connect
+pub fn process_channels(project: &Arc<RwLock<Project>>, root: &PathBuf, channels: &[ProjectItem], cache: &ProcessedCache) -> Vec<RequestType> {
+	let defaults = ProjectDefaults::new(&project.read());
+	let mut batcher = FileBatcher::new();
+	for channel in channels {
+		for file in get_files_for_channel(root, channel) {
+			let tup = get_associated_item(project, &file, &channel.channel_name);
+			process_tup(tup, &mut batcher, cache, &defaults, &file, root);
+		}
+	}
+	batcher.retrieve_batch()
+}
+
+// what a computer subscribed to the given channels ends up with, files from later channels replace earlier ones at the same path like they would on the computer
+pub fn get_channel_output(project: &Arc<RwLock<Project>>, channels: &[String], cache: &ProcessedCache) -> Result<Vec<DataSync>, Vec<String>> {
+	let (root, unknown) = {
+		let p = project.read();
+		let unknown: Vec<String> = channels.iter()
+			.filter(|e| !p.items.iter().any(|item| &item.channel_name == *e))
+			.map(|e| format!("channel {} doesn't exist", e))
+			.collect();
+		(env::current_dir().unwrap().join(&p.root_dir), unknown)
+	};
+	if !unknown.is_empty() {
+		return Err(unknown);
+	}
+	let full_list = get_full_channel_list(channels.to_vec(), project, &mut HashSet::new());
+	let mut files: Vec<DataSync> = Vec::new();
+	for request in process_channels(project, &root, &full_list, cache) {
+		let (RequestType::Library { data } | RequestType::Resource { data } | RequestType::Script { data }) = request else {
+			continue;
+		};
+		files.retain(|e| e.file_path != data.file_path);
+		files.push(data);
+	}
+	// files that failed to process are just left out by process_tup, which is fine for a computer that's still connected but not for an export
+	let mut failed: Vec<String> = get_cc_paths_for_channels(&root, &full_list).into_iter()
+		.filter(|path| !files.iter().any(|e| &e.file_path == path))
+		.map(|path| format!("{} could not be processed", path))
+		.collect();
+	if !failed.is_empty() {
+		failed.sort();
+		return Err(failed);
+	}
+	Ok(files)
+}
+
+// where a file synced to cc_path goes when exporting to a directory, cc paths can't leave it
+pub fn get_export_path(out: &Path, cc_path: &str) -> Option<PathBuf> {
+	let mut path = out.to_path_buf();
+	for part in cc_path.split(['/', '\\']) {
+		match part {
+			"" | "." => {}
+			".." => return None,
+			_ => path.push(part)
+		}
+	}
+	if path == out { None } else { Some(path) }
+}
+
+pub fn get_all_files(project: &Project) -> Vec<PathBuf> {
+	let mut v = Vec::new();
+	let project_root = env::current_dir().unwrap().join(&project.root_dir);
+	for channel in &project.items {
+		let mut files = get_files_for_channel(&project_root, channel);
+		v.append(&mut files);
+	}
+	v
+}
+
+pub fn get_cc_path(root: &Path, channel: &ProjectItem, file: &Path) -> String {
+	if let Some(files) = &channel.files {
+		for fi in files {
+			if root.join(&fi.path) == file && let Some(cc_path) = &fi.cc_path {
+				return cc_path.clone();
+			}
+		}
+	}
+	file.strip_prefix(root).unwrap_or(file).to_string_lossy().to_string()
+}
+
+// the reason a client can't subscribe, sent to it as the close reason
+pub fn check_protocol_version(version: Option<u32>) -> Result<(), String> {
+	match version {
+		Some(version) if version == PROTOCOL_VERSION => Ok(()),
+		Some(version) if version > PROTOCOL_VERSION => Err(format!("sync.lua is newer than the server (protocol {} > {}), update the server", version, PROTOCOL_VERSION)),
+		_ => Err(format!("sync.lua is outdated (server uses protocol {}), re-run /download", PROTOCOL_VERSION))
+	}
+}
+
+// the file (and the channel it's synced through) that ends up at cc_path on the computer
+pub fn find_cc_path(root: &Path, channels: &[ProjectItem], cc_path: &str) -> Option<(PathBuf, String)> {
+	for channel in channels {
+		for file in get_files_for_channel(root, channel) {
+			if get_cc_path(root, channel, &file) == cc_path {
+				return Some((file, channel.channel_name.clone()));
+			}
+		}
+	}
+	None
+}
+
+// every file under cc_path when it's a directory on the computer, or the file itself
+pub fn find_cc_paths_under(root: &Path, channels: &[ProjectItem], cc_path: &str) -> Vec<(PathBuf, String)> {
+	let dir = format!("{}/", cc_path.trim_end_matches('/'));
+	let mut found = Vec::new();
+	for channel in channels {
+		for file in get_files_for_channel(root, channel) {
+			let path = get_cc_path(root, channel, &file);
+			if path == cc_path || path.starts_with(&dir) {
+				found.push((file, channel.channel_name.clone()));
+			}
+		}
+	}
+	found
+}
+
+pub fn get_cc_paths_for_channels(root: &Path, channels: &[ProjectItem]) -> HashSet<String> {
+	let mut paths = HashSet::new();
+	for channel in channels {
+		for file in get_files_for_channel(root, channel) {
+			paths.insert(get_cc_path(root, channel, &file));
+		}
+	}
+	paths
+}
+
+// resolves once shutdown() was called, including when that happened before this receiver was created
+pub async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+	let _ = shutdown.wait_for(|e| *e).await;
+}
+
+// channels whose synced output may differ between two versions of a project,
+// including channels that only exist in one of them
+pub fn get_changed_channels(old: &Project, new: &Project) -> Vec<String> {
+	let project_wide = old.root_dir != new.root_dir
+		|| old.minify != new.minify
+		|| old.deflate_trickery != new.deflate_trickery
+		|| old.require_prefix != new.require_prefix
+		|| old.prefix_exclusions != new.prefix_exclusions;
+	let mut changed = Vec::new();
+	for item in &new.items {
+		let previous = old.items.iter().find(|e| e.channel_name == item.channel_name);
+		if project_wide || previous != Some(item) {
+			changed.push(item.channel_name.clone());
+		}
+	}
+	for item in &old.items {
+		if !new.items.iter().any(|e| e.channel_name == item.channel_name) {
+			changed.push(item.channel_name.clone());
+		}
+	}
+	changed
+}
+
+// the project wide settings files fall back to, subscriptions read them once and again when the project is reloaded
+#[derive(Clone)]
+pub struct ProjectDefaults {
+	pub minify: Option<bool>,
+	pub deflate_trickery: Option<bool>,
+	pub require_prefix: Option<String>,
+	pub prefix_exclusions: Option<Vec<String>>
+}
+
+impl ProjectDefaults {
+	pub fn new(project: &Project) -> Self {
+		Self {
+			minify: project.minify,
+			deflate_trickery: project.deflate_trickery,
+			require_prefix: project.require_prefix.clone(),
+			prefix_exclusions: project.prefix_exclusions.clone()
+		}
+	}
+}
+
+// files that are synced as is, so an edit made on a computer can be written back to the source without losing anything
+pub fn is_unprocessed(tup: &Option<(Option<Directory>, Option<File>, ProjectItem)>, defaults: &ProjectDefaults) -> bool {
+	let Some((dir, file, item)) = tup else {
+		return false;
+	};
+	let (minify, deflate, prefix, bundle) = match (dir, file) {
+		(Some(dir), _) => (dir.minify, dir.deflate_trickery, dir.require_prefix.clone(), false),
+		(_, Some(file)) => (file.minify, file.deflate_trickery, file.require_prefix.clone(), file.bundle.unwrap_or(false)),
+		_ => return false
+	};
+	let minify = minify.or(item.minify).or(defaults.minify).unwrap_or(false);
+	let deflate = deflate.or(item.deflate_trickery).or(defaults.deflate_trickery).unwrap_or(false);
+	let prefix = prefix.or(item.require_prefix.clone()).or(defaults.require_prefix.clone());
+	!minify && !deflate && !bundle && prefix.is_none()
+}
+
+// cc paths of every file computers subscribed to these channels may send edits of
+pub fn get_bidirectional_paths(project: &Arc<RwLock<Project>>, root: &Path, channels: &[ProjectItem]) -> Vec<String> {
+	let defaults = ProjectDefaults::new(&project.read());
+	let mut paths = Vec::new();
+	for channel in channels.iter().filter(|e| e.bidirectional == Some(true)) {
+		for file in get_files_for_channel(root, channel) {
+			let tup = get_associated_item(project, &file, &channel.channel_name);
+			if is_unprocessed(&tup, &defaults) {
+				paths.push(get_cc_path(root, channel, &file));
+			}
+		}
+	}
+	paths.sort();
+	paths.dedup();
+	paths
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UploadResult {
+	Written,
+	// the source changed since the computer got the version it edited, so the edit was saved here instead
+	Conflict(PathBuf)
+}
+
+// tag is added to conflict copies so it's clear which computer they came from
+pub fn apply_upload(file: &Path, cc_path: &str, content: &[u8], base_hash: &str, conflict_dir: &Path, tag: &str) -> std::io::Result<UploadResult> {
+	let current = std::fs::read(file).ok();
+	if current.as_deref() == Some(content) {
+		return Ok(UploadResult::Written);
+	}
+	if current.as_deref().map(get_content_hash).as_deref() == Some(base_hash) {
+		std::fs::write(file, content)?;
+		return Ok(UploadResult::Written);
+	}
+	let Some(copy) = get_export_path(conflict_dir, cc_path) else {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} isn't a valid path", cc_path)));
+	};
+	let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+	let copy = copy.with_file_name(format!("{}.{}-{}", copy.file_name().unwrap().to_string_lossy(), tag, time));
+	std::fs::create_dir_all(copy.parent().unwrap())?;
+	std::fs::write(&copy, content)?;
+	Ok(UploadResult::Conflict(copy))
+}
+
+pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>, batcher: &mut FileBatcher, cache: &ProcessedCache, defaults: &ProjectDefaults, path: &PathBuf, project_root: &PathBuf) {
+	let (project_minify, project_deflate) = (&defaults.minify, &defaults.deflate_trickery);
+	let (project_prefix, project_prefix_exclude) = (&defaults.require_prefix, &defaults.prefix_exclusions);
 	if let Some(res) = tup {
 		if let Some(dir) = res.0 {
 			let item = res.2;
@@ -436,7 +859,12 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					false
 				}
 			};
-			let file_content = process_file(&path, &project_root, item.item_type, minify, deflate_bs, false, {
+			let Ok(file_content) = cache.get_or_process(path, project_root, &ProcessOptions {
+				item_type: item.item_type,
+				minify,
+				deflate: deflate_bs,
+				bundle: false,
+				require_prefix: {
 					if let Some(prefix) = dir.require_prefix {
 						Some(prefix)
 					}
@@ -450,7 +878,7 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 						None
 					}
 				},
-				{
+				prefix_exclusions: {
 					if let Some(exclusions) = dir.prefix_exclusions {
 						Some(exclusions)
 					}
@@ -464,14 +892,24 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 						None
 					}
 				}
-				);
+			}) else {
+				// already reported when it was processed
+				return;
+			};
+			let file_hash = get_content_hash(&file_content);
+			let total_size = file_content.len();
+			let checksum = get_checksum(&file_content);
 			let cc_path = path.strip_prefix(&project_root).unwrap();
 			match item.item_type {
 				ProjectItemType::Resource => {
 					let reqtype = RequestType::Resource {
 						data: DataSync {
 							file_path: cc_path.to_string_lossy().to_string(),
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -480,7 +918,11 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					let reqtype = RequestType::Library {
 						data: DataSync {
 							file_path: cc_path.to_string_lossy().to_string(),
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -489,7 +931,11 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					let reqtype = RequestType::Script {
 						data: DataSync {
 							file_path: cc_path.to_string_lossy().to_string(),
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -534,21 +980,26 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					false
 				}
 			};
-			let file_content = process_file(&path, &project_root, item.item_type, minify, deflate_bs, bundle, {
-				if let Some(prefix) = file.require_prefix {
-					Some(prefix)
-				}
-				else if let Some(prefix) = item.require_prefix {
-					Some(prefix)
-				}
-				else if let Some(prefix) = project_prefix {
-					Some(prefix.clone())
-				}
-				else {
-					None
-				}
-			},
-			{
+			let Ok(file_content) = cache.get_or_process(path, project_root, &ProcessOptions {
+				item_type: item.item_type,
+				minify,
+				deflate: deflate_bs,
+				bundle,
+				require_prefix: {
+					if let Some(prefix) = file.require_prefix {
+						Some(prefix)
+					}
+					else if let Some(prefix) = item.require_prefix {
+						Some(prefix)
+					}
+					else if let Some(prefix) = project_prefix {
+						Some(prefix.clone())
+					}
+					else {
+						None
+					}
+				},
+				prefix_exclusions: {
 					if let Some(exclusions) = file.prefix_exclusions {
 						Some(exclusions)
 					}
@@ -561,7 +1012,14 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					else {
 						None
 					}
-				});
+				}
+			}) else {
+				// already reported when it was processed
+				return;
+			};
+			let file_hash = get_content_hash(&file_content);
+			let total_size = file_content.len();
+			let checksum = get_checksum(&file_content);
 			let cc_path = {
 				if let Some(p) = file.cc_path {
 					p
@@ -575,7 +1033,11 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					let reqtype = RequestType::Resource {
 						data: DataSync {
 							file_path: cc_path,
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -584,7 +1046,11 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					let reqtype = RequestType::Library {
 						data: DataSync {
 							file_path: cc_path,
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -593,7 +1059,11 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 					let reqtype = RequestType::Script {
 						data: DataSync {
 							file_path: cc_path,
-							file_data: file_content
+							file_data: file_content,
+							file_hash,
+							total_size,
+							checksum,
+							transfer_id: 0
 						}
 					};
 					batcher.add_request(reqtype);
@@ -603,20 +1073,6 @@ pub fn process_tup(tup: Option<(Option<Directory>, Option<File>, ProjectItem)>,
 	}
 }
 
-fn comment_gotos(file_content: &mut String) {
-	let goto_replaced = regex_replace_all!("goto (?<label_name>.+)", file_content, |_, label_name| format!("--autocommentedgoto {}", label_name));
-	let label_replaced = regex_replace_all!("::(?<label_name>.+)::", &goto_replaced, |_, label_name| format!("--autocommented::{}::", label_name));
-	let res = label_replaced.to_string();
-	*file_content = res;
-}
-
-fn uncomment_gotos(file_content: &mut String) {
-	let goto_replaced = regex_replace_all!("--autocommentedgoto (?<label_name>.+)", file_content, |_, label_name| format!(" goto {} ", label_name));
-	let label_replaced = regex_replace_all!("--autocommented::(?<label_name>.+)::", &goto_replaced, |_, label_name| format!(" ::{}:: ", label_name));
-	let res = label_replaced.to_string();
-	*file_content = res;
-}
-
 pub fn get_darklua_cfg() -> Configuration {
 	let red: Box<dyn Rule> = Box::new(RemoveEmptyDo::default());
 	let ce: Box<dyn Rule> = Box::new(ComputeExpression::default());
@@ -628,7 +1084,7 @@ pub fn get_darklua_cfg() -> Configuration {
 	let rv: Box<dyn Rule> = Box::new(RenameVariables::default().with_function_names());
 	let rif: Box<dyn Rule> = Box::new(RemoveIfExpression::default());
 	let gla: Box<dyn Rule> = Box::new(GroupLocalAssignment::default());
-	let rc: Box<dyn Rule> = Box::new(RemoveComments::default().with_exception("--autocommented"));
+	let rc: Box<dyn Rule> = Box::new(RemoveComments::default());
 	let rt: Box<dyn Rule> = Box::new(RemoveTypes::default());
 	let rfcp: Box<dyn Rule> = Box::new(RemoveFunctionCallParens::default());
 	let rmd: Box<dyn Rule> = Box::new(RemoveMethodDefinition::default());
@@ -647,4 +1103,5 @@ pub fn get_darklua_cfg() -> Configuration {
 		.with_rule(gla)
 		.with_rule(rif)
 		.with_rule(faer)
+		.with_generator(darklua_core::GeneratorParameters::Dense { column_span: usize::MAX })
 }
\ No newline at end of file
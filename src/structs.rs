@@ -1,6 +1,8 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct File {
 	pub path: String,
 	#[serde(default)]
@@ -17,7 +19,7 @@ pub struct File {
 	pub prefix_exclusions: Option<Vec<String>>
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Directory {
 	pub path: String,
 	#[serde(default)]
@@ -30,7 +32,7 @@ pub struct Directory {
 	pub prefix_exclusions: Option<Vec<String>>
 }
 
-#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ProjectItemType {
 	Resource,
 	Library,
@@ -47,7 +49,7 @@ impl ToString for ProjectItemType {
 	}
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct ProjectItem {
 	#[serde(rename = "type")]
 	pub item_type: ProjectItemType,
@@ -65,14 +67,40 @@ pub struct ProjectItem {
 	#[serde(default)]
 	pub require_prefix: Option<String>,
 	#[serde(default)]
-	pub prefix_exclusions: Option<Vec<String>>
+	pub prefix_exclusions: Option<Vec<String>>,
+	// computers can send edits to this channel's files back, only for files that are synced as is
+	#[serde(default)]
+	pub bidirectional: Option<bool>,
+	// needed on top of the project's auth_token to subscribe to this channel or anything that requires it
+	#[serde(default)]
+	pub auth_token: Option<String>
 }
 
 fn get_default_sync_interval() -> u64 {
 	1
 }
 
-#[derive(Deserialize, Clone, Debug)]
+fn get_default_debounce_ms() -> u64 {
+	100
+}
+
+fn get_default_poll_interval_ms() -> u64 {
+	1000
+}
+
+// how root_dir and project.ron are watched, native events don't arrive on some bind mounts, wsl paths and network shares
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+	Native,
+	// compares file contents every poll_interval_ms
+	Poll,
+	// native, polling when it can't be set up
+	#[default]
+	Auto
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Project {
 	pub root_dir: String,
 	pub items: Vec<ProjectItem>,
@@ -88,8 +116,28 @@ pub struct Project {
 	#[serde(default)]
 	pub lz_on_deflate: Option<bool>,
 	pub port: u16,
+	// ip addresses to listen on, 127.0.0.1 when not set
+	#[serde(default)]
+	pub bind_address: Option<Vec<String>>,
+	// needed for /commands as `Authorization: Bearer <token>` when set, wherever the request comes from
+	#[serde(default)]
+	pub admin_token: Option<String>,
+	// lets requests from this machine use /commands without admin_token. tunnels like ngrok connect from localhost too,
+	// so this is off unless asked for
+	#[serde(default)]
+	pub allow_local_commands: bool,
+	// needed by every route when set, computers pass it with `/download?token=<token>`
+	#[serde(default)]
+	pub auth_token: Option<String>,
 	#[serde(default = "get_default_sync_interval")]
-	pub sync_interval: u64
+	pub sync_interval: u64,
+	// how long a file has to go without changes before it's processed, saving usually touches a file several times
+	#[serde(default = "get_default_debounce_ms")]
+	pub debounce_ms: u64,
+	#[serde(default)]
+	pub watch_mode: WatchMode,
+	#[serde(default = "get_default_poll_interval_ms")]
+	pub poll_interval_ms: u64
 }
 
 #[derive(PartialEq, Eq)]
@@ -98,7 +146,19 @@ pub struct DataSync {
 	#[serde(rename = "fp")]
 	pub file_path: String,
 	#[serde(rename = "fd")]
-	pub file_data: String
+	pub file_data: Vec<u8>,
+	// hash of the whole processed file, clients keep it in their manifest to skip unchanged files on reconnect
+	#[serde(rename = "h")]
+	pub file_hash: String,
+	// size and adler32 of the whole file, file_data may only be the first chunk of it.
+	// adler32 since libdeflate on the client can already compute it
+	#[serde(rename = "s")]
+	pub total_size: usize,
+	#[serde(rename = "c")]
+	pub checksum: u32,
+	// set when file_data is only the first piece, the chunks with the rest carry the same id
+	#[serde(rename = "t")]
+	pub transfer_id: u32
 }
 
 impl Into<Vec<u8>> for RequestType {
@@ -108,7 +168,8 @@ impl Into<Vec<u8>> for RequestType {
 			Self::Resource { data } => Self::vec_from_fsync(data),
 			Self::Script { data } => Self::vec_from_fsync(data),
 			Self::Deletion { files } => Self::vec_from_del(files),
-			Self::Chunk { file_data } => Self::vec_from_chunk(file_data)
+			Self::Chunk { transfer_id, index, total, file_data } => Self::vec_from_chunk(transfer_id, index, total, file_data),
+			Self::Rename { renames } => Self::vec_from_rename(renames)
 		}
 	}
 }
@@ -121,8 +182,14 @@ impl RequestType {
 		let fd_len = (data.file_data.len() as u32).to_be_bytes();
 		v.extend_from_slice(&fp_len);
 		v.extend_from_slice(&fd_len);
+		v.extend_from_slice(&(data.total_size as u32).to_be_bytes());
+		v.extend_from_slice(&data.checksum.to_be_bytes());
+		v.extend_from_slice(&data.transfer_id.to_be_bytes());
+		// sync.lua reads exactly 16 bytes of hash, anything else would shift the path and data after it
+		assert_eq!(data.file_hash.len(), 16, "hash of {} isn't 16 bytes", data.file_path);
+		v.extend_from_slice(data.file_hash.as_bytes());
 		v.extend_from_slice(data.file_path.as_bytes());
-		v.extend_from_slice(data.file_data.as_bytes());
+		v.extend_from_slice(&data.file_data);
 		v
 	}
 
@@ -139,12 +206,28 @@ impl RequestType {
 		v
 	}
 
-	fn vec_from_chunk(chunk: String) -> Vec<u8> {
+	fn vec_from_rename(renames: Vec<(String, String)>) -> Vec<u8> {
+		let mut v = Vec::new();
+		v.push(3);
+		v.extend_from_slice(&(renames.len() as u32).to_be_bytes());
+		for (from, to) in &renames {
+			v.extend_from_slice(&(from.len() as u32).to_be_bytes());
+			v.extend_from_slice(from.as_bytes());
+			v.extend_from_slice(&(to.len() as u32).to_be_bytes());
+			v.extend_from_slice(to.as_bytes());
+		}
+		v
+	}
+
+	fn vec_from_chunk(transfer_id: u32, index: u32, total: u32, chunk: Vec<u8>) -> Vec<u8> {
 		let mut v = Vec::new();
 		v.push(2);
+		v.extend_from_slice(&transfer_id.to_be_bytes());
+		v.extend_from_slice(&index.to_be_bytes());
+		v.extend_from_slice(&total.to_be_bytes());
 		let str_len = (chunk.len() as u32).to_be_bytes();
 		v.extend_from_slice(&str_len);
-		v.extend_from_slice(chunk.as_bytes());
+		v.extend_from_slice(&chunk);
 		v
 	}
 }
@@ -169,8 +252,152 @@ pub enum RequestType {
 		#[serde(rename = "f")]
 		files: Vec<String> 
 	},
+	// index counts the first piece (sent as a normal file) as 0, total includes it
 	Chunk { 
+		#[serde(rename = "t")]
+		transfer_id: u32,
+		#[serde(rename = "i")]
+		index: u32,
+		#[serde(rename = "n")]
+		total: u32,
 		#[serde(rename = "fd")]
-		file_data: String
+		file_data: Vec<u8>
+	},
+	// paths on the computer to move somewhere else, directories are moved along with everything in them
+	Rename {
+		#[serde(rename = "r")]
+		renames: Vec<(String, String)>
+	}
+}
+// bumped whenever the binary format changes in a way an older sync.lua would misparse, sync.lua has its own copy of it
+pub const PROTOCOL_VERSION: u32 = 6;
+// what the server can do for clients on this version, so sync.lua can tell what it can rely on without comparing versions
+pub const SERVER_CAPABILITIES: &[&str] = &["deflate", "binary", "chunk_framing", "checksum", "manifest", "resend", "bidirectional", "commands", "rename"];
+
+// sent by the server as json text messages
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+	Hello {
+		version: u32,
+		capabilities: Vec<String>
+	},
+	// every file the computer may send edits of, replaces the previous list
+	Bidirectional {
+		files: Vec<String>
+	},
+	// what the computer is subscribed to after a subscribe or unsubscribe, without the channels that were refused
+	Channels {
+		channels: Vec<String>
+	},
+	// answered with a command_result with the same id
+	Run {
+		id: u64,
+		program: String,
+		args: Vec<String>
+	},
+	Reboot {
+		id: u64
+	}
+}
+
+// what /commands asks computers to do
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteCommand {
+	Run {
+		program: String,
+		#[serde(default)]
+		args: Vec<String>
+	},
+	Reboot
+}
+
+// every computer subscribed to channel runs the command, or only the one with the given id
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CommandRequest {
+	pub channel: String,
+	#[serde(default)]
+	pub computer: Option<u32>,
+	#[serde(flatten)]
+	pub command: RemoteCommand
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CommandResult {
+	pub computer: String,
+	pub computer_id: Option<u32>,
+	pub success: bool,
+	pub output: String
+}
+
+// sent by the client as json text messages
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+	// always the first message, sync.lua from before the handshake existed never sends it
+	Hello {
+		version: u32
+	},
+	Manifest {
+		#[serde(default)]
+		files: HashMap<String, ManifestEntry>
+	},
+	// the file didn't match its checksum once every chunk was written
+	Resend {
+		path: String
+	},
+	// files that were written and verified
+	Ack {
+		files: Vec<String>
+	},
+	Subscribe {
+		channels: Vec<String>
+	},
+	Unsubscribe {
+		channels: Vec<String>
+	},
+	ClientInfo {
+		id: u32,
+		#[serde(default)]
+		label: Option<String>
+	},
+	// output of programs on the computer, error is set for printError
+	Log {
+		line: String,
+		#[serde(default)]
+		error: bool
+	},
+	// how a command sent by the server went, reboots are answered before rebooting
+	CommandResult {
+		id: u64,
+		success: bool,
+		#[serde(default)]
+		output: String
+	},
+	// sent as a binary message since content doesn't have to be text, base_hash is the hash of the version that was edited
+	Upload {
+		path: String,
+		base_hash: String,
+		content: Vec<u8>
 	}
-}
\ No newline at end of file
+}
+
+impl ClientMessage {
+	// tag (0 for uploads) | path length u32 | base hash (16 bytes) | path | content
+	pub fn from_binary(data: &[u8]) -> Option<Self> {
+		if data.len() < 21 || data[0] != 0 {
+			return None;
+		}
+		let path_len = u32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+		let base_hash = String::from_utf8(data[5..21].to_vec()).ok()?;
+		let path = String::from_utf8(data.get(21..21 + path_len)?.to_vec()).ok()?;
+		Some(Self::Upload { path, base_hash, content: data[21 + path_len..].to_vec() })
+	}
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+	pub hash: String,
+	pub size: u64
+}